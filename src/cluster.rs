@@ -0,0 +1,389 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::voxel::VoxelHashMap;
+
+/// Union-find over `0..n`, with path halving and union by size.
+#[derive(Clone, Debug)]
+pub struct DisjointSet {
+    parent: Vec<usize>,
+    size: Vec<usize>,
+}
+
+impl DisjointSet {
+    pub fn new(n: usize) -> Self {
+        Self {
+            parent: (0..n).collect(),
+            size: vec![1; n],
+        }
+    }
+
+    /// Representative of the set containing `i`.
+    pub fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    /// Merge the sets containing `a` and `b`, returning whether they were separate.
+    pub fn union(&mut self, a: usize, b: usize) -> bool {
+        let (mut a, mut b) = (self.find(a), self.find(b));
+        if a == b {
+            return false;
+        }
+        if self.size[a] < self.size[b] {
+            std::mem::swap(&mut a, &mut b);
+        }
+        self.parent[b] = a;
+        self.size[a] += self.size[b];
+        true
+    }
+}
+
+/// A connected group of boids.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cluster {
+    pub size: usize,
+    pub centroid: Vec2,
+    pub velocity: Vec2,
+}
+
+/// Connected components of boids, linking any two within a radius of each other.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Clustering {
+    /// Index into `clusters` of each boid, in the order given
+    pub labels: Vec<usize>,
+    /// From largest to smallest
+    pub clusters: Vec<Cluster>,
+}
+
+impl Clustering {
+    /// Label `boids` (entity, position and velocity), searching the cells of `voxels` within
+    /// `radius` of each boid for links. Entities in `voxels` that aren't in `boids` are ignored.
+    pub fn new(boids: &[(Entity, Vec2, Vec2)], voxels: &VoxelHashMap, radius: f32) -> Self {
+        let index = boids
+            .iter()
+            .enumerate()
+            .map(|(i, (entity, ..))| (*entity, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut sets = DisjointSet::new(boids.len());
+        for (i, (_, position, _)) in boids.iter().enumerate() {
+            for key in voxels.get_neighbor_keys_within(*position, radius) {
+                let Some(entities) = voxels.map.get(&key) else {
+                    continue;
+                };
+                for j in entities.iter().filter_map(|entity| index.get(entity)) {
+                    // Each pair once
+                    if *j > i && position.distance_squared(boids[*j].1) <= radius * radius {
+                        sets.union(i, *j);
+                    }
+                }
+            }
+        }
+
+        // Accumulate each root's members, then rank the roots by size
+        let mut totals = HashMap::<usize, (usize, Vec2, Vec2)>::default();
+        let roots = (0..boids.len()).map(|i| sets.find(i)).collect::<Vec<_>>();
+        for (root, (_, position, velocity)) in roots.iter().zip(boids) {
+            let total = totals.entry(*root).or_default();
+            total.0 += 1;
+            total.1 += *position;
+            total.2 += *velocity;
+        }
+        let mut ranked = totals.into_iter().collect::<Vec<_>>();
+        // Ties broken by root, so labelling is deterministic
+        ranked.sort_unstable_by_key(|(root, (size, ..))| (std::cmp::Reverse(*size), *root));
+
+        let rank = ranked
+            .iter()
+            .enumerate()
+            .map(|(label, (root, _))| (*root, label))
+            .collect::<HashMap<_, _>>();
+        Self {
+            labels: roots.iter().map(|root| rank[root]).collect(),
+            clusters: ranked
+                .into_iter()
+                .map(|(_, (size, position, velocity))| Cluster {
+                    size,
+                    centroid: position / size as f32,
+                    velocity: velocity / size as f32,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Identity of a cluster that persists from frame to frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FlockId(pub u64);
+
+impl fmt::Display for FlockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&format!("#{}", self.0))
+    }
+}
+
+/// A flock broke apart. The largest child keeps the parent's id.
+#[derive(Event, Clone, Debug, PartialEq)]
+pub struct FlockSplit {
+    pub parent: FlockId,
+    pub children: Vec<FlockId>,
+}
+
+/// Flocks joined. The child keeps the id of the parent that contributed the most boids.
+#[derive(Event, Clone, Debug, PartialEq)]
+pub struct FlockMerged {
+    pub parents: Vec<FlockId>,
+    pub child: FlockId,
+}
+
+/// Stable ids for the clusters of each frame, and the splits and merges since the last one.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClusterChanges {
+    /// Id of each cluster, indexed like [`Clustering::clusters`]
+    pub ids: Vec<FlockId>,
+    pub splits: Vec<FlockSplit>,
+    pub merges: Vec<FlockMerged>,
+}
+
+/// Follows clusters across frames by their members. A cluster keeps an id when it holds most of
+/// the boids that were in that flock, and that flock contributed more of its boids than any
+/// other; otherwise it gets a new one. Splits and merges are only reported between clusters of
+/// at least `min_size` boids, each sharing at least `min_size` boids with the other side, so
+/// single boids wandering between flocks don't count.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct ClusterTracker {
+    pub min_size: usize,
+    next_id: u64,
+    /// Flock of each boid in the last frame
+    previous: HashMap<Entity, FlockId>,
+    previous_sizes: HashMap<FlockId, usize>,
+}
+
+impl ClusterTracker {
+    pub fn new(min_size: usize) -> Self {
+        Self {
+            min_size,
+            ..default()
+        }
+    }
+
+    /// Match `clustering` of `boids` (in the order it was computed from) against the last frame.
+    pub fn update(&mut self, boids: &[Entity], clustering: &Clustering) -> ClusterChanges {
+        // Boids shared by each (current cluster, previous flock) pair
+        let mut overlaps = HashMap::<(usize, FlockId), usize>::default();
+        for (entity, label) in boids.iter().zip(&clustering.labels) {
+            if let Some(previous) = self.previous.get(entity) {
+                *overlaps.entry((*label, *previous)).or_default() += 1;
+            }
+        }
+        let mut overlaps = overlaps.into_iter().collect::<Vec<_>>();
+        // Largest overlaps first, ties broken by cluster then id so matching is deterministic
+        overlaps.sort_unstable_by_key(|((cluster, id), n)| (std::cmp::Reverse(*n), *cluster, *id));
+
+        let mut best_parent = HashMap::<usize, FlockId>::default();
+        let mut best_child = HashMap::<FlockId, usize>::default();
+        for ((cluster, id), _) in &overlaps {
+            best_parent.entry(*cluster).or_insert(*id);
+            best_child.entry(*id).or_insert(*cluster);
+        }
+
+        let ids = (0..clustering.clusters.len())
+            .map(|cluster| match best_parent.get(&cluster) {
+                Some(id) if best_child[id] == cluster => *id,
+                _ => {
+                    self.next_id += 1;
+                    FlockId(self.next_id)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut children = HashMap::<FlockId, Vec<FlockId>>::default();
+        let mut parents = HashMap::<usize, Vec<FlockId>>::default();
+        for ((cluster, id), n) in &overlaps {
+            if *n < self.min_size
+                || clustering.clusters[*cluster].size < self.min_size
+                || self.previous_sizes[id] < self.min_size
+            {
+                continue;
+            }
+            children.entry(*id).or_default().push(ids[*cluster]);
+            parents.entry(*cluster).or_default().push(*id);
+        }
+
+        let mut splits = children
+            .into_iter()
+            .filter(|(_, children)| children.len() > 1)
+            .map(|(parent, mut children)| {
+                children.sort_unstable();
+                FlockSplit { parent, children }
+            })
+            .collect::<Vec<_>>();
+        splits.sort_unstable_by_key(|split| split.parent);
+        let mut merges = parents
+            .into_iter()
+            .filter(|(_, parents)| parents.len() > 1)
+            .map(|(cluster, mut parents)| {
+                parents.sort_unstable();
+                FlockMerged {
+                    parents,
+                    child: ids[cluster],
+                }
+            })
+            .collect::<Vec<_>>();
+        merges.sort_unstable_by_key(|merge| merge.child);
+
+        self.previous = boids
+            .iter()
+            .zip(&clustering.labels)
+            .map(|(entity, label)| (*entity, ids[*label]))
+            .collect();
+        self.previous_sizes = ids
+            .iter()
+            .zip(&clustering.clusters)
+            .map(|(id, cluster)| (*id, cluster.size))
+            .collect();
+
+        ClusterChanges {
+            ids,
+            splits,
+            merges,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disjoint_set() {
+        let mut sets = DisjointSet::new(5);
+        assert!(sets.union(0, 1));
+        assert!(sets.union(3, 4));
+        assert!(sets.union(1, 4));
+        assert!(!sets.union(0, 3));
+        assert_eq!(sets.find(0), sets.find(4));
+        assert_ne!(sets.find(0), sets.find(2));
+    }
+
+    #[test]
+    fn test_clustering() {
+        let mut voxels = VoxelHashMap::new();
+        voxels.cell_size = 4.;
+        // A chain of three linked boids, a pair and a loner
+        let positions = [
+            Vec2::new(0., 0.),
+            Vec2::new(5., 0.),
+            Vec2::new(10., 0.),
+            Vec2::new(100., 100.),
+            Vec2::new(100., 105.),
+            Vec2::new(-50., 0.),
+        ];
+        let boids = positions
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (Entity::from_raw(i as u32), *p, Vec2::new(i as f32, 0.)))
+            .collect::<Vec<_>>();
+        for (entity, position, _) in &boids {
+            voxels.insert(*position, *entity);
+        }
+
+        let clustering = Clustering::new(&boids, &voxels, 6.);
+        assert_eq!(clustering.labels, vec![0, 0, 0, 1, 1, 2]);
+        assert_eq!(
+            clustering.clusters,
+            vec![
+                Cluster {
+                    size: 3,
+                    centroid: Vec2::new(5., 0.),
+                    velocity: Vec2::new(1., 0.),
+                },
+                Cluster {
+                    size: 2,
+                    centroid: Vec2::new(100., 102.5),
+                    velocity: Vec2::new(3.5, 0.),
+                },
+                Cluster {
+                    size: 1,
+                    centroid: Vec2::new(-50., 0.),
+                    velocity: Vec2::new(5., 0.),
+                },
+            ]
+        );
+
+        // Out of reach with a smaller radius
+        let clustering = Clustering::new(&boids, &voxels, 4.);
+        assert_eq!(clustering.clusters.len(), 6);
+    }
+
+    /// Clustering of entities `0..labels.len()` with the given labels, which must be ranked.
+    fn clustering(labels: &[usize]) -> (Vec<Entity>, Clustering) {
+        let n_clusters = labels.iter().max().map_or(0, |max| max + 1);
+        let clusters = (0..n_clusters)
+            .map(|c| Cluster {
+                size: labels.iter().filter(|label| **label == c).count(),
+                centroid: Vec2::ZERO,
+                velocity: Vec2::ZERO,
+            })
+            .collect();
+        let entities = (0..labels.len() as u32).map(Entity::from_raw).collect();
+        (
+            entities,
+            Clustering {
+                labels: labels.to_vec(),
+                clusters,
+            },
+        )
+    }
+
+    #[test]
+    fn test_tracker_split_and_merge() {
+        let mut tracker = ClusterTracker::new(2);
+
+        // Two flocks of five and three, and a loner
+        let (entities, first) = clustering(&[0, 0, 0, 0, 0, 1, 1, 1, 2]);
+        let changes = tracker.update(&entities, &first);
+        let [a, b, loner] = changes.ids[..] else {
+            panic!("{changes:?}");
+        };
+        assert!(changes.splits.is_empty() && changes.merges.is_empty());
+
+        // The same flocks, ranked differently, keep their ids
+        let (entities, same) = clustering(&[1, 1, 1, 1, 1, 0, 0, 0, 2]);
+        assert_eq!(tracker.update(&entities, &same).ids, vec![b, a, loner]);
+
+        // The first flock splits three to two, and the loner leaves without an event
+        let (entities, split) = clustering(&[0, 0, 0, 2, 2, 1, 1, 1, 3]);
+        let changes = tracker.update(&entities, &split);
+        assert_eq!(changes.ids[0], a);
+        assert_eq!(changes.ids[1], b);
+        let c = changes.ids[2];
+        assert_eq!(
+            changes.splits,
+            vec![FlockSplit {
+                parent: a,
+                children: if a < c { vec![a, c] } else { vec![c, a] },
+            }]
+        );
+        assert!(changes.merges.is_empty());
+
+        // Then the smaller piece joins the other flock
+        let (entities, merged) = clustering(&[0, 0, 0, 1, 1, 1, 1, 1, 2]);
+        let changes = tracker.update(&entities, &merged);
+        assert_eq!(changes.ids[..2], [a, b]);
+        assert_eq!(
+            changes.merges,
+            vec![FlockMerged {
+                parents: vec![b, c],
+                child: b,
+            }]
+        );
+        assert!(changes.splits.is_empty());
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float32Type, UInt32Type, UInt64Type};
+use arrow_array::{ArrayRef, Float32Array, Float64Array, RecordBatch, UInt32Array, UInt64Array};
+use arrow_ipc::reader::FileReader;
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef};
+
+use crate::recorder::{TrajectoryFormat, TrajectoryRow};
+use crate::timeseries::MetricsRow;
+
+/// Writes record batches to an Arrow IPC file or, with the `parquet` feature, a Parquet file.
+/// Metadata on the schema (e.g. the seed and parameters) is stored in the file, where pandas
+/// and polars can read it back.
+pub struct BatchWriter {
+    sink: Sink,
+}
+
+enum Sink {
+    Ipc(FileWriter<BufWriter<File>>),
+    /// Not `Sync` on its own, which Bevy resources need to be
+    #[cfg(feature = "parquet")]
+    Parquet(std::sync::Mutex<parquet::arrow::ArrowWriter<File>>),
+}
+
+impl BatchWriter {
+    /// Create `path`, which must be [`TrajectoryFormat::Arrow`] or [`TrajectoryFormat::Parquet`].
+    pub fn create(
+        path: &Path,
+        format: TrajectoryFormat,
+        schema: SchemaRef,
+    ) -> Result<Self, ArrowError> {
+        let sink = match format {
+            TrajectoryFormat::Arrow => Sink::Ipc(FileWriter::try_new(
+                BufWriter::new(File::create(path)?),
+                &schema,
+            )?),
+            #[cfg(feature = "parquet")]
+            TrajectoryFormat::Parquet => {
+                use parquet::basic::Compression;
+                use parquet::file::properties::WriterProperties;
+
+                let properties = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                Sink::Parquet(std::sync::Mutex::new(parquet::arrow::ArrowWriter::try_new(
+                    File::create(path)?,
+                    schema,
+                    Some(properties),
+                )?))
+            }
+            _ => {
+                return Err(ArrowError::InvalidArgumentError(format!(
+                    "{format:?} is not a columnar format"
+                )))
+            }
+        };
+        Ok(Self { sink })
+    }
+
+    pub fn write(&mut self, batch: &RecordBatch) -> Result<(), ArrowError> {
+        match &mut self.sink {
+            Sink::Ipc(writer) => writer.write(batch),
+            #[cfg(feature = "parquet")]
+            Sink::Parquet(writer) => Ok(parquet_writer(writer).write(batch)?),
+        }
+    }
+
+    /// Write the footer. The file can't be read until this is called.
+    pub fn finish(&mut self) -> Result<(), ArrowError> {
+        match &mut self.sink {
+            Sink::Ipc(writer) => writer.finish(),
+            #[cfg(feature = "parquet")]
+            Sink::Parquet(writer) => parquet_writer(writer)
+                .finish()
+                .map(|_| ())
+                .map_err(Into::into),
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+fn parquet_writer(
+    writer: &mut std::sync::Mutex<parquet::arrow::ArrowWriter<File>>,
+) -> &mut parquet::arrow::ArrowWriter<File> {
+    // A panic mid-write leaves the file unusable either way
+    writer.get_mut().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Schema of trajectory files, with `metadata` attached.
+pub fn trajectory_schema(metadata: HashMap<String, String>) -> SchemaRef {
+    Arc::new(Schema::new_with_metadata(
+        vec![
+            Field::new("tick", DataType::UInt64, false),
+            Field::new("id", DataType::UInt64, false),
+            Field::new("x", DataType::Float32, false),
+            Field::new("y", DataType::Float32, false),
+            Field::new("vx", DataType::Float32, false),
+            Field::new("vy", DataType::Float32, false),
+            Field::new("n_neighbors", DataType::UInt32, false),
+        ],
+        metadata,
+    ))
+}
+
+pub fn trajectory_batch(
+    schema: SchemaRef,
+    rows: &[TrajectoryRow],
+) -> Result<RecordBatch, ArrowError> {
+    let u64s = |f: fn(&TrajectoryRow) -> u64| -> ArrayRef {
+        Arc::new(rows.iter().map(f).collect::<UInt64Array>())
+    };
+    let f32s = |f: fn(&TrajectoryRow) -> f32| -> ArrayRef {
+        Arc::new(rows.iter().map(f).collect::<Float32Array>())
+    };
+    RecordBatch::try_new(
+        schema,
+        vec![
+            u64s(|row| row.tick),
+            u64s(|row| row.id),
+            f32s(|row| row.x),
+            f32s(|row| row.y),
+            f32s(|row| row.vx),
+            f32s(|row| row.vy),
+            Arc::new(
+                rows.iter()
+                    .map(|row| row.n_neighbors)
+                    .collect::<UInt32Array>(),
+            ),
+        ],
+    )
+}
+
+/// Schema of metrics files, with `metadata` attached.
+pub fn metrics_schema(metadata: HashMap<String, String>) -> SchemaRef {
+    let f32 = |name| Field::new(name, DataType::Float32, false);
+    Arc::new(Schema::new_with_metadata(
+        vec![
+            Field::new("tick", DataType::UInt64, false),
+            Field::new("time", DataType::Float64, false),
+            f32("polarization"),
+            f32("milling"),
+            f32("nearest_neighbor_distance"),
+            f32("group_radius"),
+            f32("mean_speed"),
+            Field::new("clusters", DataType::UInt32, false),
+            Field::new("largest_cluster", DataType::UInt32, false),
+            f32("fps"),
+        ],
+        metadata,
+    ))
+}
+
+pub fn metrics_batch(schema: SchemaRef, rows: &[MetricsRow]) -> Result<RecordBatch, ArrowError> {
+    let f32s = |f: fn(&MetricsRow) -> f32| -> ArrayRef {
+        Arc::new(rows.iter().map(f).collect::<Float32Array>())
+    };
+    let u32s = |f: fn(&MetricsRow) -> u32| -> ArrayRef {
+        Arc::new(rows.iter().map(f).collect::<UInt32Array>())
+    };
+    RecordBatch::try_new(
+        schema,
+        vec![
+            Arc::new(rows.iter().map(|row| row.tick).collect::<UInt64Array>()),
+            Arc::new(rows.iter().map(|row| row.time).collect::<Float64Array>()),
+            f32s(|row| row.polarization),
+            f32s(|row| row.milling),
+            f32s(|row| row.nearest_neighbor_distance),
+            f32s(|row| row.group_radius),
+            f32s(|row| row.mean_speed),
+            u32s(|row| row.clusters),
+            u32s(|row| row.largest_cluster),
+            f32s(|row| row.fps),
+        ],
+    )
+}
+
+/// Read every row of an Arrow IPC or Parquet trajectory file, along with its schema metadata.
+pub fn read_trajectories(
+    path: &Path,
+    format: TrajectoryFormat,
+) -> Result<(Vec<TrajectoryRow>, HashMap<String, String>), ArrowError> {
+    let file = File::open(path)?;
+    let (metadata, batches): (_, Vec<RecordBatch>) = match format {
+        TrajectoryFormat::Arrow => {
+            let reader = FileReader::try_new(file, None)?;
+            (
+                reader.schema().metadata().clone(),
+                reader.collect::<Result<_, _>>()?,
+            )
+        }
+        #[cfg(feature = "parquet")]
+        TrajectoryFormat::Parquet => {
+            let builder =
+                parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file)?;
+            (
+                builder.schema().metadata().clone(),
+                builder.build()?.collect::<Result<_, _>>()?,
+            )
+        }
+        _ => {
+            return Err(ArrowError::InvalidArgumentError(format!(
+                "{format:?} is not a columnar format"
+            )))
+        }
+    };
+
+    let mut rows = Vec::new();
+    for batch in batches {
+        let column = |name: &str| {
+            batch
+                .column_by_name(name)
+                .ok_or_else(|| ArrowError::SchemaError(format!("missing column {name}")))
+        };
+        let u64s = |name| {
+            column(name)?
+                .as_primitive_opt::<UInt64Type>()
+                .ok_or_else(|| ArrowError::SchemaError(format!("{name} should be u64")))
+        };
+        let f32s = |name| {
+            column(name)?
+                .as_primitive_opt::<Float32Type>()
+                .ok_or_else(|| ArrowError::SchemaError(format!("{name} should be f32")))
+        };
+        let (tick, id) = (u64s("tick")?, u64s("id")?);
+        let (x, y, vx, vy) = (f32s("x")?, f32s("y")?, f32s("vx")?, f32s("vy")?);
+        let n_neighbors = column("n_neighbors")?
+            .as_primitive_opt::<UInt32Type>()
+            .ok_or_else(|| ArrowError::SchemaError("n_neighbors should be u32".into()))?;
+        rows.extend((0..batch.num_rows()).map(|i| TrajectoryRow {
+            tick: tick.value(i),
+            id: id.value(i),
+            x: x.value(i),
+            y: y.value(i),
+            vx: vx.value(i),
+            vy: vy.value(i),
+            n_neighbors: n_neighbors.value(i),
+        }));
+    }
+    Ok((rows, metadata))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::{row, temp_dir};
+
+    fn round_trip(format: TrajectoryFormat, name: &str) {
+        let dir = temp_dir(&format!("columnar_{name}"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+
+        let metadata = HashMap::from([("seed".to_string(), "7".to_string())]);
+        let schema = trajectory_schema(metadata.clone());
+        let mut writer = BatchWriter::create(&path, format, schema.clone()).unwrap();
+        let rows = (0..10).map(|i| row(i / 5, i % 5)).collect::<Vec<_>>();
+        for chunk in rows.chunks(4) {
+            writer
+                .write(&trajectory_batch(schema.clone(), chunk).unwrap())
+                .unwrap();
+        }
+        writer.finish().unwrap();
+
+        let (read, read_metadata) = read_trajectories(&path, format).unwrap();
+        assert_eq!(read, rows);
+        assert_eq!(read_metadata.get("seed"), metadata.get("seed"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_arrow_round_trip() {
+        round_trip(TrajectoryFormat::Arrow, "run.arrow");
+    }
+
+    #[test]
+    fn test_metrics_batch() {
+        let rows = [MetricsRow::default(); 3];
+        let batch = metrics_batch(metrics_schema(HashMap::new()), &rows).unwrap();
+        assert_eq!(batch.num_rows(), 3);
+        assert_eq!(batch.schema().field(batch.num_columns() - 1).name(), "fps");
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet_round_trip() {
+        round_trip(TrajectoryFormat::Parquet, "run.parquet");
+    }
+}
@@ -0,0 +1,271 @@
+use std::f32::consts::PI;
+use std::io::{self, Write};
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::voxel::VoxelHashMap;
+
+/// Pair correlation function g(r) and connected velocity correlation C(r), binned by distance
+/// up to a maximum radius, as in Cavagna et al. 2010 ("Scale-free correlations in starling
+/// flocks"). Frames are accumulated, so the functions are averages over every frame added.
+///
+/// C(r) correlates each boid's velocity fluctuation about the flock's mean velocity, normalised
+/// so that C(0) = 1, and the correlation length is where it first crosses zero. g(r) is
+/// normalised by the density of boids spread uniformly over the given area, so it tends to 1 at
+/// large distances for a uniform gas, with no correction for the edges of the flock. Distances
+/// ignore wrapping around a periodic boundary.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Correlations {
+    pub bin_width: f32,
+    /// Pairs whose distance falls in each bin
+    pub pair_counts: Vec<u64>,
+    /// Sum of the normalised velocity fluctuation products of each bin's pairs
+    product_sums: Vec<f64>,
+    /// Pairs counted towards `product_sums`, skipping frames with no fluctuations at all
+    product_counts: Vec<u64>,
+    /// Pairs per unit area expected of boids spread uniformly, summed over frames
+    uniform_pair_density: f64,
+    pub frames: usize,
+}
+
+impl Correlations {
+    pub fn new(max_radius: f32, bins: usize) -> Self {
+        let bins = bins.max(1);
+        Self {
+            bin_width: max_radius / bins as f32,
+            pair_counts: vec![0; bins],
+            product_sums: vec![0.; bins],
+            product_counts: vec![0; bins],
+            uniform_pair_density: 0.,
+            frames: 0,
+        }
+    }
+
+    pub fn bins(&self) -> usize {
+        self.pair_counts.len()
+    }
+
+    pub fn max_radius(&self) -> f32 {
+        self.bin_width * self.bins() as f32
+    }
+
+    /// Distance at the middle of `bin`.
+    pub fn radius(&self, bin: usize) -> f32 {
+        (bin as f32 + 0.5) * self.bin_width
+    }
+
+    /// Add one frame of `boids` (entity, position and velocity), spread over `area`. Only the
+    /// cells of `voxels` within the maximum radius of each boid are searched for pairs.
+    pub fn add_frame(&mut self, boids: &[(Entity, Vec2, Vec2)], voxels: &VoxelHashMap, area: f32) {
+        let n = boids.len();
+        if n < 2 || area <= 0. {
+            return;
+        }
+        let index = boids
+            .iter()
+            .enumerate()
+            .map(|(i, (entity, ..))| (*entity, i))
+            .collect::<HashMap<_, _>>();
+
+        let mean_velocity = boids.iter().map(|(.., velocity)| *velocity).sum::<Vec2>() / n as f32;
+        let mean_square_fluctuation = boids
+            .iter()
+            .map(|(.., velocity)| (*velocity - mean_velocity).length_squared())
+            .sum::<f32>()
+            / n as f32;
+        // Normalised so that C(0) = 1
+        let fluctuations = (mean_square_fluctuation > 0.).then(|| {
+            let scale = mean_square_fluctuation.sqrt().recip();
+            boids
+                .iter()
+                .map(|(.., velocity)| (*velocity - mean_velocity) * scale)
+                .collect::<Vec<_>>()
+        });
+
+        let max_radius = self.max_radius();
+        for (i, (_, position, _)) in boids.iter().enumerate() {
+            for key in voxels.get_neighbor_keys_within(*position, max_radius) {
+                let Some(entities) = voxels.map.get(&key) else {
+                    continue;
+                };
+                for j in entities.iter().filter_map(|entity| index.get(entity)) {
+                    // Each pair once
+                    if *j <= i {
+                        continue;
+                    }
+                    let bin = (position.distance(boids[*j].1) / self.bin_width) as usize;
+                    if bin >= self.bins() {
+                        continue;
+                    }
+                    self.pair_counts[bin] += 1;
+                    if let Some(fluctuations) = &fluctuations {
+                        self.product_sums[bin] += fluctuations[i].dot(fluctuations[*j]) as f64;
+                        self.product_counts[bin] += 1;
+                    }
+                }
+            }
+        }
+        self.uniform_pair_density += (n * (n - 1)) as f64 / 2. / area as f64;
+        self.frames += 1;
+    }
+
+    /// Merge the frames of `other`, which must have the same bins.
+    pub fn add(&mut self, other: &Correlations) {
+        debug_assert_eq!(self.bins(), other.bins());
+        for bin in 0..self.bins().min(other.bins()) {
+            self.pair_counts[bin] += other.pair_counts[bin];
+            self.product_sums[bin] += other.product_sums[bin];
+            self.product_counts[bin] += other.product_counts[bin];
+        }
+        self.uniform_pair_density += other.uniform_pair_density;
+        self.frames += other.frames;
+    }
+
+    /// g(r) of each bin, or NaN before any frames.
+    pub fn pair_correlation(&self) -> Vec<f32> {
+        (0..self.bins())
+            .map(|bin| {
+                let inner = bin as f32 * self.bin_width;
+                let outer = inner + self.bin_width;
+                let annulus = PI * (outer * outer - inner * inner);
+                let expected = self.uniform_pair_density * annulus as f64;
+                if expected > 0. {
+                    (self.pair_counts[bin] as f64 / expected) as f32
+                } else {
+                    f32::NAN
+                }
+            })
+            .collect()
+    }
+
+    /// C(r) of each bin, or NaN where no pairs were found.
+    pub fn velocity_correlation(&self) -> Vec<f32> {
+        self.product_sums
+            .iter()
+            .zip(&self.product_counts)
+            .map(|(sum, count)| {
+                if *count > 0 {
+                    (sum / *count as f64) as f32
+                } else {
+                    f32::NAN
+                }
+            })
+            .collect()
+    }
+
+    /// Distance at which C(r) first crosses zero, interpolated between bin centres, or `None`
+    /// if it stays positive within the maximum radius.
+    pub fn correlation_length(&self) -> Option<f32> {
+        let mut last: Option<(f32, f32)> = None;
+        for (bin, c) in self.velocity_correlation().into_iter().enumerate() {
+            if c.is_nan() {
+                continue;
+            }
+            let r = self.radius(bin);
+            if c <= 0. {
+                return Some(match last {
+                    Some((last_r, last_c)) => last_r + (r - last_r) * last_c / (last_c - c),
+                    None => r,
+                });
+            }
+            last = Some((r, c));
+        }
+        None
+    }
+
+    /// Write a CSV line per bin with the columns `r,g,c,pairs`, where `r` is the middle of the
+    /// bin, preceded by comment lines giving the frame count and correlation length.
+    pub fn write_csv(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "# frames: {}", self.frames)?;
+        match self.correlation_length() {
+            Some(length) => writeln!(out, "# correlation_length: {length}")?,
+            None => writeln!(
+                out,
+                "# correlation_length: none within {}",
+                self.max_radius()
+            )?,
+        }
+        writeln!(out, "r,g,c,pairs")?;
+        let g = self.pair_correlation();
+        let c = self.velocity_correlation();
+        for bin in 0..self.bins() {
+            writeln!(
+                out,
+                "{},{},{},{}",
+                self.radius(bin),
+                g[bin],
+                c[bin],
+                self.pair_counts[bin]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::voxels;
+
+    #[test]
+    fn test_pair_counts() {
+        // Three boids on a line, 1 and 3 apart
+        let boids = [0., 1., 4.].map(|x| {
+            (
+                Entity::from_raw(x as u32),
+                Vec2::new(x, 0.),
+                Vec2::new(x, 0.),
+            )
+        });
+        let mut correlations = Correlations::new(5., 5);
+        correlations.add_frame(&boids, &voxels(&boids, 2.), 100.);
+        assert_eq!(correlations.pair_counts, vec![0, 1, 0, 1, 1]);
+        assert_eq!(correlations.frames, 1);
+
+        // Three pairs over an area of 100, so 0.03 expected per unit area
+        let g = correlations.pair_correlation();
+        let annulus = PI * (2. * 2. - 1.);
+        assert!((g[1] - 1. / (0.03 * annulus)).abs() < 1e-4);
+        assert_eq!(g[0], 0.);
+
+        // Adding a frame twice changes the counts but not the functions
+        let mut twice = correlations.clone();
+        twice.add(&correlations);
+        assert_eq!(twice.pair_counts, vec![0, 2, 0, 2, 2]);
+        assert_eq!(twice.pair_correlation(), g);
+        assert_eq!(
+            twice.velocity_correlation()[4],
+            correlations.velocity_correlation()[4]
+        );
+    }
+
+    #[test]
+    fn test_velocity_correlation() {
+        // Two groups 10 apart, each moving together but against the mean velocity
+        let boids = [(0., 1.), (1., 1.), (10., -1.), (11., -1.)].map(|(x, vx)| {
+            (
+                Entity::from_raw(x as u32),
+                Vec2::new(x, 0.),
+                Vec2::new(vx, 0.) + Vec2::Y,
+            )
+        });
+        let mut correlations = Correlations::new(12., 6);
+        correlations.add_frame(&boids, &voxels(&boids, 4.), 1000.);
+        let c = correlations.velocity_correlation();
+        // Neighbours within a group are fully correlated, and the groups anticorrelated
+        assert_eq!(c[0], 1.);
+        assert!(c[1].is_nan());
+        assert_eq!(c[4], -1.);
+        assert_eq!(c[5], -1.);
+        // Crosses zero half way between the centres of bins 0 and 4
+        let length = correlations.correlation_length().unwrap();
+        assert!((length - 5.).abs() < 1e-4);
+
+        let mut csv = Vec::new();
+        correlations.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.contains("# correlation_length: 5\n"));
+        assert!(csv.contains("\n1,"));
+    }
+}
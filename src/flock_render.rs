@@ -0,0 +1,345 @@
+use bevy::asset::load_internal_asset;
+use bevy::core_pipeline::core_2d::Transparent2d;
+use bevy::ecs::system::lifetimeless::{Read, SRes};
+use bevy::ecs::system::SystemParamItem;
+use bevy::math::FloatOrd;
+use bevy::prelude::*;
+use bevy::render::mesh::allocator::MeshAllocator;
+use bevy::render::mesh::{MeshVertexBufferLayoutRef, RenderMesh, RenderMeshBufferInfo};
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::render_phase::{
+    AddRenderCommand, DrawFunctions, PhaseItem, PhaseItemExtraIndex, RenderCommand,
+    RenderCommandResult, SetItemPipeline, TrackedRenderPass, ViewSortedRenderPhases,
+};
+use bevy::render::render_resource::{
+    BufferUsages, PipelineCache, RawBufferVec, RenderPipelineDescriptor, SpecializedMeshPipeline,
+    SpecializedMeshPipelineError, SpecializedMeshPipelines, VertexAttribute, VertexBufferLayout,
+    VertexFormat, VertexStepMode,
+};
+use bevy::render::renderer::{RenderDevice, RenderQueue};
+use bevy::render::sync_world::{MainEntity, RenderEntity, SyncToRenderWorld};
+use bevy::render::view::ExtractedView;
+use bevy::render::{ExtractSchedule, MainWorld, Render, RenderApp, RenderSet};
+use bevy::sprite::{
+    Mesh2dPipeline, Mesh2dPipelineKey, RenderMesh2dInstances, SetMesh2dViewBindGroup,
+};
+use bytemuck::{Pod, Zeroable};
+
+const FLOCK_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(0x6b1f_42d3_9c0e_4a57_b8e1_d25f_7a93_c604);
+
+/// Draws every entity with a [`Mesh2d`] and [`FlockInstances`] as one instanced draw call: the
+/// shared mesh is drawn once per instance, placed and coloured from a per-instance vertex
+/// buffer, so the whole flock needs a single mesh and no materials.
+///
+/// `Material2d` always draws one instance per entity and can't bind a second vertex buffer, so
+/// this queues its own pipeline, built on [`Mesh2dPipeline`], into the transparent 2D phase.
+/// Instance buffers are plain vertex buffers, so it also works on WebGL2.
+pub struct FlockRenderPlugin;
+
+impl Plugin for FlockRenderPlugin {
+    fn build(&self, app: &mut App) {
+        // Headless runs have no renderer
+        if app.get_sub_app(RenderApp).is_none() {
+            return;
+        }
+        load_internal_asset!(
+            app,
+            FLOCK_SHADER_HANDLE,
+            "flock_render.wgsl",
+            Shader::from_wgsl
+        );
+        app.sub_app_mut(RenderApp)
+            .add_render_command::<Transparent2d, DrawFlock>()
+            .init_resource::<SpecializedMeshPipelines<FlockPipeline>>()
+            .add_systems(ExtractSchedule, extract_flock_instances)
+            .add_systems(
+                Render,
+                (
+                    queue_flocks.in_set(RenderSet::QueueMeshes),
+                    prepare_flock_instances.in_set(RenderSet::PrepareResources),
+                ),
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        if let Some(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app.init_resource::<FlockPipeline>();
+        }
+    }
+}
+
+/// One boid as uploaded to the GPU: its 2D transform and colour.
+#[derive(Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct FlockInstance {
+    /// Where the mesh's +X axis maps to, including rotation and scale
+    pub x_axis: [f32; 2],
+    /// Where the mesh's +Y axis maps to
+    pub y_axis: [f32; 2],
+    pub translation: [f32; 3],
+    pub color: [f32; 4],
+}
+
+impl FlockInstance {
+    pub fn new(transform: &Transform, color: LinearRgba) -> Self {
+        let affine = transform.compute_affine();
+        Self {
+            x_axis: affine.matrix3.x_axis.xy().to_array(),
+            y_axis: affine.matrix3.y_axis.xy().to_array(),
+            translation: affine.translation.to_array(),
+            color: color.to_f32_array(),
+        }
+    }
+}
+
+/// Instances to draw the entity's [`Mesh2d`] with, refilled every frame. The buffer is moved
+/// into the render world when extracted and the previous frame's allocation handed back
+/// empty, so nothing is copied on the CPU.
+#[derive(Component, Clone, Debug, Default)]
+#[require(SyncToRenderWorld)]
+pub struct FlockInstances(pub Vec<FlockInstance>);
+
+/// Render world copy of [`FlockInstances`], with the GPU buffer it's written to.
+#[derive(Component)]
+struct FlockInstanceBuffer(RawBufferVec<FlockInstance>);
+
+fn extract_flock_instances(
+    mut commands: Commands,
+    mut main_world: ResMut<MainWorld>,
+    mut q_buffers: Query<&mut FlockInstanceBuffer>,
+) {
+    let mut q_flocks = main_world.query::<(&RenderEntity, &mut FlockInstances)>();
+    for (render_entity, mut instances) in q_flocks.iter_mut(&mut main_world) {
+        match q_buffers.get_mut(render_entity.id()) {
+            Ok(mut buffer) => std::mem::swap(buffer.0.values_mut(), &mut instances.0),
+            Err(_) => {
+                let mut buffer = RawBufferVec::new(BufferUsages::VERTEX);
+                std::mem::swap(buffer.values_mut(), &mut instances.0);
+                commands
+                    .entity(render_entity.id())
+                    .insert(FlockInstanceBuffer(buffer));
+            }
+        }
+        instances.0.clear();
+    }
+}
+
+fn prepare_flock_instances(
+    mut q_buffers: Query<&mut FlockInstanceBuffer>,
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+) {
+    for mut buffer in q_buffers.iter_mut() {
+        buffer.0.write_buffer(&device, &queue);
+    }
+}
+
+fn queue_flocks(
+    draw_functions: Res<DrawFunctions<Transparent2d>>,
+    pipeline: Res<FlockPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<FlockPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<RenderMesh>>,
+    mesh_instances: Res<RenderMesh2dInstances>,
+    q_flocks: Query<(Entity, &MainEntity), With<FlockInstanceBuffer>>,
+    mut phases: ResMut<ViewSortedRenderPhases<Transparent2d>>,
+    q_views: Query<(Entity, &ExtractedView, &Msaa)>,
+) {
+    let draw_flock = draw_functions.read().id::<DrawFlock>();
+    for (view_entity, view, msaa) in q_views.iter() {
+        let Some(phase) = phases.get_mut(&view_entity) else {
+            continue;
+        };
+        let view_key = Mesh2dPipelineKey::from_msaa_samples(msaa.samples())
+            | Mesh2dPipelineKey::from_hdr(view.hdr)
+            | Mesh2dPipelineKey::BLEND_ALPHA;
+        for (entity, main_entity) in q_flocks.iter() {
+            // Missing when the entity is hidden
+            let Some(mesh_instance) = mesh_instances.get(main_entity) else {
+                continue;
+            };
+            let Some(mesh) = meshes.get(mesh_instance.mesh_asset_id) else {
+                continue;
+            };
+            let key =
+                view_key | Mesh2dPipelineKey::from_primitive_topology(mesh.primitive_topology());
+            let pipeline_id =
+                match pipelines.specialize(&pipeline_cache, &pipeline, key, &mesh.layout) {
+                    Ok(id) => id,
+                    Err(err) => {
+                        error!("{err}");
+                        continue;
+                    }
+                };
+            phase.add(Transparent2d {
+                entity: (entity, *main_entity),
+                draw_function: draw_flock,
+                pipeline: pipeline_id,
+                sort_key: FloatOrd(mesh_instance.transforms.world_from_local.translation.z),
+                batch_range: 0..1,
+                extra_index: PhaseItemExtraIndex::NONE,
+            });
+        }
+    }
+}
+
+#[derive(Resource)]
+struct FlockPipeline {
+    mesh2d_pipeline: Mesh2dPipeline,
+}
+
+impl FromWorld for FlockPipeline {
+    fn from_world(world: &mut World) -> Self {
+        Self {
+            mesh2d_pipeline: world.resource::<Mesh2dPipeline>().clone(),
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for FlockPipeline {
+    type Key = Mesh2dPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayoutRef,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut descriptor = self.mesh2d_pipeline.specialize(key, layout)?;
+        descriptor.label = Some("flock_pipeline".into());
+        descriptor.vertex.shader = FLOCK_SHADER_HANDLE;
+        descriptor.vertex.buffers.push(VertexBufferLayout {
+            array_stride: size_of::<FlockInstance>() as u64,
+            step_mode: VertexStepMode::Instance,
+            // Locations 0-4 are the mesh's own attributes
+            attributes: vec![
+                VertexAttribute {
+                    format: VertexFormat::Float32x2,
+                    offset: 0,
+                    shader_location: 5,
+                },
+                VertexAttribute {
+                    format: VertexFormat::Float32x2,
+                    offset: 8,
+                    shader_location: 6,
+                },
+                VertexAttribute {
+                    format: VertexFormat::Float32x3,
+                    offset: 16,
+                    shader_location: 7,
+                },
+                VertexAttribute {
+                    format: VertexFormat::Float32x4,
+                    offset: 28,
+                    shader_location: 8,
+                },
+            ],
+        });
+        if let Some(fragment) = &mut descriptor.fragment {
+            fragment.shader = FLOCK_SHADER_HANDLE;
+        }
+        // Instances are placed in world space, so the per-mesh bind group isn't needed
+        descriptor.layout.truncate(1);
+        Ok(descriptor)
+    }
+}
+
+type DrawFlock = (
+    SetItemPipeline,
+    SetMesh2dViewBindGroup<0>,
+    DrawMeshInstanced,
+);
+
+/// Draws the item's mesh once for each of its [`FlockInstanceBuffer`]'s instances.
+struct DrawMeshInstanced;
+
+impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
+    type Param = (
+        SRes<RenderAssets<RenderMesh>>,
+        SRes<RenderMesh2dInstances>,
+        SRes<MeshAllocator>,
+    );
+    type ViewQuery = ();
+    type ItemQuery = Read<FlockInstanceBuffer>;
+
+    fn render<'w>(
+        item: &P,
+        _view: (),
+        instances: Option<&'w FlockInstanceBuffer>,
+        (meshes, mesh_instances, mesh_allocator): SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let mesh_allocator = mesh_allocator.into_inner();
+        let Some(mesh_instance) = mesh_instances.into_inner().get(&item.main_entity()) else {
+            return RenderCommandResult::Skip;
+        };
+        let Some(gpu_mesh) = meshes.into_inner().get(mesh_instance.mesh_asset_id) else {
+            return RenderCommandResult::Skip;
+        };
+        let Some(vertex_slice) = mesh_allocator.mesh_vertex_slice(&mesh_instance.mesh_asset_id)
+        else {
+            return RenderCommandResult::Skip;
+        };
+        let Some(instances) = instances.filter(|instances| !instances.0.is_empty()) else {
+            return RenderCommandResult::Skip;
+        };
+        let Some(instance_buffer) = instances.0.buffer() else {
+            return RenderCommandResult::Skip;
+        };
+        let instance_range = 0..instances.0.len() as u32;
+
+        pass.set_vertex_buffer(0, vertex_slice.buffer.slice(..));
+        pass.set_vertex_buffer(1, instance_buffer.slice(..));
+        match &gpu_mesh.buffer_info {
+            RenderMeshBufferInfo::Indexed {
+                index_format,
+                count,
+            } => {
+                let Some(index_slice) =
+                    mesh_allocator.mesh_index_slice(&mesh_instance.mesh_asset_id)
+                else {
+                    return RenderCommandResult::Skip;
+                };
+                pass.set_index_buffer(index_slice.buffer.slice(..), 0, *index_format);
+                pass.draw_indexed(
+                    index_slice.range.start..index_slice.range.start + count,
+                    vertex_slice.range.start as i32,
+                    instance_range,
+                );
+            }
+            RenderMeshBufferInfo::NonIndexed => pass.draw(vertex_slice.range, instance_range),
+        }
+        RenderCommandResult::Success
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_instance_transform() {
+        let transform = Transform::from_xyz(10., 5., -1.)
+            .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2))
+            .with_scale(Vec3::splat(2.));
+        let instance = FlockInstance::new(&transform, LinearRgba::RED);
+
+        // Rotated a quarter turn and doubled: +X maps to +2Y, +Y to -2X
+        assert!(Vec2::from(instance.x_axis).abs_diff_eq(Vec2::new(0., 2.), 1e-6));
+        assert!(Vec2::from(instance.y_axis).abs_diff_eq(Vec2::new(-2., 0.), 1e-6));
+        assert_eq!(instance.translation, [10., 5., -1.]);
+        assert_eq!(instance.color, LinearRgba::RED.to_f32_array());
+    }
+
+    #[test]
+    fn test_instance_layout() {
+        // Matches the instance vertex attributes: 2 + 2 + 3 + 4 floats, tightly packed
+        assert_eq!(size_of::<FlockInstance>(), 11 * 4);
+        let instance = FlockInstance::new(&Transform::IDENTITY, LinearRgba::WHITE);
+        let floats: &[f32] = bytemuck::cast_slice(std::slice::from_ref(&instance));
+        assert_eq!(
+            floats,
+            [1., 0., 0., 1., 0., 0., 0., 1., 1., 1., 1.].as_slice()
+        );
+    }
+}
@@ -0,0 +1,30 @@
+// Instanced flock rendering: the shared boid mesh is placed and coloured by per-instance
+// attributes. See `FlockRenderPlugin`.
+#import bevy_sprite::mesh2d_view_bindings::view
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+    @location(5) x_axis: vec2<f32>,
+    @location(6) y_axis: vec2<f32>,
+    @location(7) translation: vec3<f32>,
+    @location(8) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+};
+
+@vertex
+fn vertex(vertex: Vertex) -> VertexOutput {
+    let offset = vertex.x_axis * vertex.position.x + vertex.y_axis * vertex.position.y;
+    var out: VertexOutput;
+    out.clip_position = view.clip_from_world * vec4<f32>(vertex.translation + vec3<f32>(offset, 0.0), 1.0);
+    out.color = vertex.color;
+    return out;
+}
+
+@fragment
+fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
+    return in.color;
+}
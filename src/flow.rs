@@ -0,0 +1,468 @@
+use std::fmt;
+
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, LoadContext};
+use bevy::prelude::*;
+
+use crate::npy::{read_npy, NpyError};
+
+/// Registers [`FlowGrid`] as an asset loadable from `.flow.png`, `.flow.csv` and `.flow.npy` files.
+pub struct FlowFieldPlugin;
+
+impl Plugin for FlowFieldPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<FlowGrid>()
+            .register_asset_loader(FlowGridLoader);
+    }
+}
+
+/// Spatial pattern of an external flow (wind, current) that carries boids along.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FlowPattern {
+    /// The same velocity everywhere. Rotates with the field's phase when animated.
+    Uniform(Vec2),
+    /// Rankine vortex: solid body rotation inside `radius`, decaying as 1/r outside.
+    Vortex { center: Vec2, radius: f32 },
+    /// Horizontal flow whose speed varies linearly with height.
+    Shear { width: f32 },
+    /// Divergence-free turbulence from the curl of a noise potential.
+    CurlNoise { scale: f32 },
+    /// Velocities sampled on a regular grid.
+    Grid(FlowGrid),
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct FlowField {
+    pub pattern: FlowPattern,
+    pub enabled: bool,
+    /// Speed scale of the flow.
+    pub strength: f32,
+    /// Rate at which `phase` advances, in radians per second (0 keeps the field static).
+    pub time_scale: f32,
+    pub phase: f32,
+}
+
+impl Default for FlowField {
+    fn default() -> Self {
+        Self {
+            pattern: FlowPattern::Uniform(Vec2::X),
+            enabled: false,
+            strength: 1.,
+            time_scale: 0.,
+            phase: 0.,
+        }
+    }
+}
+
+impl FlowField {
+    pub fn new(pattern: FlowPattern, strength: f32) -> Self {
+        Self {
+            pattern,
+            enabled: true,
+            strength,
+            ..default()
+        }
+    }
+
+    pub fn advance(&mut self, dt: f32) {
+        self.phase += self.time_scale * dt;
+    }
+
+    /// Flow velocity at `pos`, or zero when the field is disabled.
+    pub fn sample(&self, pos: Vec2) -> Vec2 {
+        if !self.enabled {
+            return Vec2::ZERO;
+        }
+
+        let velocity = match &self.pattern {
+            FlowPattern::Uniform(direction) => Vec2::from_angle(self.phase).rotate(*direction),
+            FlowPattern::Vortex { center, radius } => {
+                let offset = pos - *center;
+                let r = offset.length();
+                let tangent = offset.perp() / r.max(f32::EPSILON);
+                let speed = if r < *radius { r / radius } else { radius / r };
+                tangent * speed * self.phase.cos()
+            }
+            FlowPattern::Shear { width } => {
+                Vec2::new((pos.y / width).clamp(-1., 1.) * self.phase.cos(), 0.)
+            }
+            FlowPattern::CurlNoise { scale } => curl_noise(pos / *scale, self.phase),
+            FlowPattern::Grid(grid) => grid.sample(pos),
+        };
+
+        velocity * self.strength
+    }
+}
+
+/// Regular grid of flow velocities mapped onto a rectangle of the world.
+///
+/// Grids loaded from files keep the file's orientation (first row at the top) and span the unit
+/// square until given world `bounds`.
+#[derive(Asset, TypePath, Clone, Debug, PartialEq)]
+pub struct FlowGrid {
+    pub width: usize,
+    pub height: usize,
+    /// Row-major velocities, starting at the bottom left corner of `bounds`.
+    pub data: Vec<Vec2>,
+    pub bounds: Rect,
+}
+
+impl FlowGrid {
+    pub fn new(width: usize, height: usize, data: Vec<Vec2>, bounds: Rect) -> Self {
+        assert_eq!(data.len(), width * height);
+        Self {
+            width,
+            height,
+            data,
+            bounds,
+        }
+    }
+
+    /// Decode a PNG whose red and green channels encode the x and y velocity components,
+    /// with 128 meaning zero.
+    pub fn from_png(bytes: &[u8]) -> Result<Self, FlowGridError> {
+        let image = image::load_from_memory_with_format(bytes, image::ImageFormat::Png)?.to_rgba8();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let data = image
+            .pixels()
+            .map(|pixel| {
+                Vec2::new(
+                    pixel[0] as f32 / 255. * 2. - 1.,
+                    pixel[1] as f32 / 255. * 2. - 1.,
+                )
+            })
+            .collect();
+        Ok(Self::from_top_rows(width, height, data))
+    }
+
+    /// Parse a CSV grid where each line is a row of `vx,vy` pairs: `vx0,vy0,vx1,vy1,...`.
+    pub fn from_csv(text: &str) -> Result<Self, FlowGridError> {
+        let mut width = None;
+        let mut data = Vec::new();
+        let mut height = 0;
+
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let values = line
+                .split(',')
+                .map(|value| value.trim().parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| FlowGridError::Parse(format!("line {}: {e}", line_number + 1)))?;
+            if values.len() % 2 != 0 || width.is_some_and(|width| width * 2 != values.len()) {
+                return Err(FlowGridError::Parse(format!(
+                    "line {}: rows must hold the same number of vx,vy pairs",
+                    line_number + 1
+                )));
+            }
+
+            width = Some(values.len() / 2);
+            data.extend(values.chunks_exact(2).map(|v| Vec2::new(v[0], v[1])));
+            height += 1;
+        }
+
+        Ok(Self::from_top_rows(width.unwrap_or(0), height, data))
+    }
+
+    /// Read a `.npy` array shaped `(height, width, 2)`.
+    pub fn from_npy(bytes: &[u8]) -> Result<Self, FlowGridError> {
+        let array = read_npy(bytes)?;
+        let &[height, width, 2] = array.shape.as_slice() else {
+            return Err(FlowGridError::Parse(format!(
+                "expected shape (height, width, 2), found {:?}",
+                array.shape
+            )));
+        };
+        let data = array
+            .data
+            .chunks_exact(2)
+            .map(|v| Vec2::new(v[0], v[1]))
+            .collect();
+        Ok(Self::from_top_rows(width, height, data))
+    }
+
+    /// Build a grid from rows listed top to bottom, as in images and text files.
+    fn from_top_rows(width: usize, height: usize, data: Vec<Vec2>) -> Self {
+        let data = if width == 0 {
+            data
+        } else {
+            data.chunks_exact(width).rev().flatten().copied().collect()
+        };
+        Self::new(width, height, data, Rect::new(0., 0., 1., 1.))
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> Vec2 {
+        self.data[y * self.width + x]
+    }
+
+    /// Bilinearly interpolated velocity at `pos`, clamped to the edges of the grid.
+    pub fn sample(&self, pos: Vec2) -> Vec2 {
+        if self.width == 0 || self.height == 0 {
+            return Vec2::ZERO;
+        }
+
+        // Grid nodes sit at the corners of `bounds`
+        let uv = (pos - self.bounds.min) / self.bounds.size();
+        let x = (uv.x * (self.width - 1) as f32).clamp(0., (self.width - 1) as f32);
+        let y = (uv.y * (self.height - 1) as f32).clamp(0., (self.height - 1) as f32);
+
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(self.width - 1), (y0 + 1).min(self.height - 1));
+        let (tx, ty) = (x - x0 as f32, y - y0 as f32);
+
+        let bottom = self.get(x0, y0).lerp(self.get(x1, y0), tx);
+        let top = self.get(x0, y1).lerp(self.get(x1, y1), tx);
+        bottom.lerp(top, ty)
+    }
+}
+
+#[derive(Default)]
+pub struct FlowGridLoader;
+
+impl AssetLoader for FlowGridLoader {
+    type Asset = FlowGrid;
+    type Settings = ();
+    type Error = FlowGridError;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<FlowGrid, FlowGridError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+
+        let extension = load_context
+            .path()
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .unwrap_or_default();
+        match extension {
+            "png" => FlowGrid::from_png(&bytes),
+            "csv" => FlowGrid::from_csv(
+                std::str::from_utf8(&bytes).map_err(|e| FlowGridError::Parse(e.to_string()))?,
+            ),
+            "npy" => FlowGrid::from_npy(&bytes),
+            _ => Err(FlowGridError::Parse(format!(
+                "unsupported extension {extension:?}"
+            ))),
+        }
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["flow.png", "flow.csv", "flow.npy"]
+    }
+}
+
+#[derive(Debug)]
+pub enum FlowGridError {
+    Io(std::io::Error),
+    Image(image::ImageError),
+    Npy(NpyError),
+    Parse(String),
+}
+
+impl fmt::Display for FlowGridError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlowGridError::Io(e) => write!(f, "could not read flow grid: {e}"),
+            FlowGridError::Image(e) => write!(f, "could not decode flow grid image: {e}"),
+            FlowGridError::Npy(e) => write!(f, "could not read flow grid array: {e}"),
+            FlowGridError::Parse(e) => write!(f, "could not parse flow grid: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for FlowGridError {}
+
+impl From<std::io::Error> for FlowGridError {
+    fn from(e: std::io::Error) -> Self {
+        FlowGridError::Io(e)
+    }
+}
+
+impl From<image::ImageError> for FlowGridError {
+    fn from(e: image::ImageError) -> Self {
+        FlowGridError::Image(e)
+    }
+}
+
+impl From<NpyError> for FlowGridError {
+    fn from(e: NpyError) -> Self {
+        FlowGridError::Npy(e)
+    }
+}
+
+/// Curl of a scalar noise potential, giving a divergence-free velocity with magnitude of order 1.
+pub fn curl_noise(pos: Vec2, t: f32) -> Vec2 {
+    let eps = 1e-2;
+    let dx = value_noise(pos + Vec2::new(eps, 0.), t) - value_noise(pos - Vec2::new(eps, 0.), t);
+    let dy = value_noise(pos + Vec2::new(0., eps), t) - value_noise(pos - Vec2::new(0., eps), t);
+    Vec2::new(dy, -dx) / (2. * eps)
+}
+
+/// Smooth 3D value noise in [0, 1], sliced at `t`.
+pub fn value_noise(pos: Vec2, t: f32) -> f32 {
+    let p = pos.extend(t);
+    let i = p.floor();
+    let f = p - i;
+    // Quintic fade keeps the derivative (and so the curl) continuous
+    let u = f * f * f * (f * (f * 6. - 15.) + 10.);
+
+    let (x, y, z) = (i.x as i32, i.y as i32, i.z as i32);
+    let corner = |dx: i32, dy: i32, dz: i32| hash(x + dx, y + dy, z + dz);
+
+    let x00 = corner(0, 0, 0) + (corner(1, 0, 0) - corner(0, 0, 0)) * u.x;
+    let x10 = corner(0, 1, 0) + (corner(1, 1, 0) - corner(0, 1, 0)) * u.x;
+    let x01 = corner(0, 0, 1) + (corner(1, 0, 1) - corner(0, 0, 1)) * u.x;
+    let x11 = corner(0, 1, 1) + (corner(1, 1, 1) - corner(0, 1, 1)) * u.x;
+    let y0 = x00 + (x10 - x00) * u.y;
+    let y1 = x01 + (x11 - x01) * u.y;
+    y0 + (y1 - y0) * u.z
+}
+
+fn hash(x: i32, y: i32, z: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^= h >> 15;
+    h as f32 / u32::MAX as f32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disabled_field_is_still() {
+        let mut flow = FlowField::new(FlowPattern::Uniform(Vec2::X), 10.);
+        assert_eq!(flow.sample(Vec2::new(3., 4.)), Vec2::new(10., 0.));
+
+        flow.enabled = false;
+        assert_eq!(flow.sample(Vec2::new(3., 4.)), Vec2::ZERO);
+    }
+
+    #[test]
+    fn test_uniform_rotates_with_phase() {
+        let mut flow = FlowField::new(FlowPattern::Uniform(Vec2::X), 1.);
+        flow.time_scale = std::f32::consts::FRAC_PI_2;
+        flow.advance(1.);
+
+        let v = flow.sample(Vec2::ZERO);
+        assert!(v.abs_diff_eq(Vec2::Y, 1e-6));
+    }
+
+    #[test]
+    fn test_vortex() {
+        let flow = FlowField::new(
+            FlowPattern::Vortex {
+                center: Vec2::ZERO,
+                radius: 10.,
+            },
+            2.,
+        );
+
+        // Counter-clockwise, peaking at the core radius
+        assert!(flow
+            .sample(Vec2::new(10., 0.))
+            .abs_diff_eq(Vec2::new(0., 2.), 1e-6));
+        assert!(flow
+            .sample(Vec2::new(5., 0.))
+            .abs_diff_eq(Vec2::new(0., 1.), 1e-6));
+        assert!(flow
+            .sample(Vec2::new(20., 0.))
+            .abs_diff_eq(Vec2::new(0., 1.), 1e-6));
+        assert_eq!(flow.sample(Vec2::ZERO), Vec2::ZERO);
+    }
+
+    #[test]
+    fn test_shear() {
+        let flow = FlowField::new(FlowPattern::Shear { width: 100. }, 1.);
+        assert_eq!(flow.sample(Vec2::new(0., 50.)), Vec2::new(0.5, 0.));
+        assert_eq!(flow.sample(Vec2::new(0., -200.)), Vec2::new(-1., 0.));
+    }
+
+    #[test]
+    fn test_value_noise_range() {
+        for i in 0..100 {
+            let p = Vec2::new(i as f32 * 0.37, i as f32 * -0.73);
+            let n = value_noise(p, i as f32 * 0.1);
+            assert!((0. ..=1.).contains(&n));
+        }
+    }
+
+    #[test]
+    fn test_curl_noise_is_divergence_free() {
+        let eps = 1e-1;
+        for i in 0..10 {
+            let p = Vec2::new(i as f32 * 0.37 + 0.1, i as f32 * 0.59 + 0.2);
+            let div = (curl_noise(p + Vec2::new(eps, 0.), 0.).x
+                - curl_noise(p - Vec2::new(eps, 0.), 0.).x
+                + curl_noise(p + Vec2::new(0., eps), 0.).y
+                - curl_noise(p - Vec2::new(0., eps), 0.).y)
+                / (2. * eps);
+            assert!(div.abs() < 0.2, "divergence {div} at {p}");
+        }
+    }
+
+    #[test]
+    fn test_grid_bilinear() {
+        let grid = FlowGrid::new(
+            2,
+            2,
+            vec![Vec2::ZERO, Vec2::X, Vec2::Y, Vec2::ONE],
+            Rect::new(0., 0., 10., 10.),
+        );
+
+        assert_eq!(grid.sample(Vec2::new(0., 0.)), Vec2::ZERO);
+        assert_eq!(grid.sample(Vec2::new(10., 0.)), Vec2::X);
+        assert_eq!(grid.sample(Vec2::new(0., 10.)), Vec2::Y);
+        assert_eq!(grid.sample(Vec2::new(5., 5.)), Vec2::new(0.5, 0.5));
+        assert_eq!(grid.sample(Vec2::new(5., 0.)), Vec2::new(0.5, 0.));
+
+        // Clamped outside the bounds
+        assert_eq!(grid.sample(Vec2::new(-5., 20.)), Vec2::Y);
+    }
+
+    #[test]
+    fn test_grid_from_csv() {
+        let grid = FlowGrid::from_csv("# top row first\n0,1, 2,3\n4,5,6,7\n").unwrap();
+        assert_eq!(grid.width, 2);
+        assert_eq!(grid.height, 2);
+        // Bottom row of the file becomes the first row of the grid
+        assert_eq!(grid.get(0, 0), Vec2::new(4., 5.));
+        assert_eq!(grid.get(1, 0), Vec2::new(6., 7.));
+        assert_eq!(grid.get(0, 1), Vec2::new(0., 1.));
+        assert_eq!(grid.get(1, 1), Vec2::new(2., 3.));
+
+        assert!(FlowGrid::from_csv("0,1,2\n").is_err());
+        assert!(FlowGrid::from_csv("0,1,2,3\n0,1\n").is_err());
+        assert!(FlowGrid::from_csv("0,x\n").is_err());
+    }
+
+    #[test]
+    fn test_grid_from_png() {
+        let mut image = image::RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        image.put_pixel(1, 0, image::Rgba([0, 255, 0, 255]));
+        let mut bytes = Vec::new();
+        image
+            .write_to(
+                &mut std::io::Cursor::new(&mut bytes),
+                image::ImageFormat::Png,
+            )
+            .unwrap();
+
+        let grid = FlowGrid::from_png(&bytes).unwrap();
+        assert_eq!(grid.width, 2);
+        assert_eq!(grid.height, 1);
+        assert_eq!(grid.get(0, 0), Vec2::new(1., -1.));
+        assert_eq!(grid.get(1, 0), Vec2::new(-1., 1.));
+    }
+}
@@ -1 +1,18 @@
+pub mod cluster;
+#[cfg(feature = "arrow")]
+pub mod columnar;
+pub mod correlation;
+pub mod flock_render;
+pub mod flow;
+pub mod metrics;
+pub mod npy;
+pub mod palette;
+pub mod recorder;
+pub mod replay;
+pub mod snapshot;
+#[cfg(test)]
+mod test_util;
+pub mod timeseries;
+pub mod trail;
 pub mod voxel;
+pub mod wander;
@@ -1,14 +1,46 @@
+use bevy::app::ScheduleRunnerPlugin;
 use bevy::asset::AssetMetaCheck;
-use bevy::diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin};
+use bevy::diagnostic::{
+    Diagnostic, DiagnosticPath, Diagnostics, DiagnosticsStore, FrameTimeDiagnosticsPlugin,
+    LogDiagnosticsPlugin, RegisterDiagnostic,
+};
+use bevy::input::mouse::{AccumulatedMouseMotion, AccumulatedMouseScroll, MouseScrollUnit};
 use bevy::prelude::*;
-use bevy::utils::HashMap;
-use bevy::window::PrimaryWindow;
+use bevy::render::settings::WgpuSettings;
+use bevy::render::view::NoFrustumCulling;
+use bevy::render::RenderPlugin;
+use bevy::sprite::AlphaMode2d;
+use bevy::time::TimeUpdateStrategy;
+use bevy::ui::{FocusPolicy, RelativeCursorPosition};
+use bevy::utils::{HashMap, HashSet};
+use bevy::window::{ExitCondition, PrimaryWindow};
+use bevy::winit::WinitPlugin;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
 
+use bevy_boids::cluster::{Cluster, ClusterTracker, Clustering, FlockId, FlockMerged, FlockSplit};
+use bevy_boids::correlation::Correlations;
+use bevy_boids::flock_render::{FlockInstance, FlockInstances, FlockRenderPlugin};
+use bevy_boids::flow::{FlowField, FlowFieldPlugin, FlowGrid, FlowPattern};
+use bevy_boids::metrics::{mean_nearest_neighbor_distance, FlockMetrics};
+use bevy_boids::palette::{categorical, Palette};
+use bevy_boids::recorder::{is_sampled, TrajectoryFormat, TrajectoryRow, TrajectoryWriter};
+use bevy_boids::replay::Recording;
+use bevy_boids::snapshot::{BoidState, Snapshot};
+use bevy_boids::timeseries::{MetricsRow, MetricsWriter, RollingSeries};
+use bevy_boids::trail::{Trail, TrailMesh};
 use bevy_boids::voxel::VoxelHashMap;
+use bevy_boids::wander::{ornstein_uhlenbeck_step, wander_circle_direction};
 
 const BOID_NUMBER: usize = 10000;
 const BOID_RADIUS: f32 = 10.0;
 const BOID_SECTION_DEG: f32 = 10.0;
+/// Triangles per boid in the flock mesh.
+const BOID_SECTION_RESOLUTION: u32 = 4;
 const BOID_MAX_SPEED: f32 = 600.0;
 const BOID_MIN_SPEED: f32 = 50.0;
 const BOID_SEPARATION_FACTOR: f32 = 0.05;
@@ -18,7 +50,139 @@ const BOID_TURN_FACTOR: f32 = 5.;
 const BOID_SEPARATION_RADIUS: f32 = 10.;
 const BOID_ALIGNMENT_RADIUS: f32 = 40.;
 
-const DEBUG: bool = false;
+// Leader-follower dynamics (Couzin et al. 2005, "Effective leadership and decision-making in
+// animal groups on the move"). A fraction of the flock is "informed" and steers towards a
+// preferred heading; followers weight a leader's velocity more strongly when aligning.
+const LEADER_FRACTION: f32 = 0.05;
+const LEADER_HEADING_DEG: f32 = 0.;
+const LEADER_GOAL_FACTOR: f32 = 0.02;
+const LEADER_ALIGNMENT_WEIGHT: f32 = 5.;
+const LEADER_PLAYER_LEADERS: PlayerLeaders = PlayerLeaders::One;
+const LEADER_TURN_RATE_DEG: f32 = 90.;
+const LEADER_COLOR: Color = Color::srgb(1., 0.5, 0.);
+// Accuracy (mean cosine between follower and leader headings) at which the flock is
+// considered to be following.
+const LEADERSHIP_THRESHOLD: f32 = 0.9;
+
+// External flow (wind, currents) added to each boid's velocity when moving.
+const FLOW_STRENGTH: f32 = 100.;
+const FLOW_TIME_SCALE: f32 = 0.2;
+const FLOW_NOISE_SCALE: f32 = 200.;
+const FLOW_ARROW_SPACING: f32 = 50.;
+const FLOW_ARROW_SCALE: f32 = 0.3;
+// Vector grid mapped onto the window. PNG (R/G channels), CSV and NPY grids are supported.
+// Run with `--features hot_reload` to pick up edits to the file while running.
+const FLOW_GRID_PATH: &str = "flow/double_gyre.flow.png";
+
+// Wander keeps flocks exploring instead of settling into rigid patterns.
+// Circle: Reynolds' wander circle, whose target angle diffuses at WANDER_NOISE (rad/√s).
+// OrnsteinUhlenbeck: the turning rate is an OU process with amplitude WANDER_NOISE.
+const WANDER_MODE: WanderMode = WanderMode::Circle;
+const WANDER_NOISE: f32 = 3.;
+const WANDER_TIMESCALE: f32 = 0.5;
+const WANDER_DISTANCE: f32 = 2.;
+const WANDER_RADIUS: f32 = 1.;
+const WANDER_FACTOR: f32 = 1.;
+
+// With the attract tool, hold the left mouse button (or one finger) to attract boids and the
+// right button (or two fingers) to repel them.
+const ATTRACTOR_RADIUS: f32 = 150.;
+const ATTRACTOR_STRENGTH: f32 = 2000.;
+
+// With the brush tool, the left mouse button paints boids until the area under the brush
+// reaches BRUSH_DENSITY (boids per 100x100 px), and the right button erases them.
+const BRUSH_RADIUS: f32 = 60.;
+const BRUSH_DENSITY: f32 = 20.;
+const BRUSH_RATE: f32 = 500.;
+const BRUSH_HEADING: BrushHeading = BrushHeading::Stroke;
+
+// Camera: drag with the middle mouse button or use WASD to pan, scroll to zoom.
+const CAMERA_PAN_SPEED: f32 = 600.;
+const CAMERA_ZOOM_STEP: f32 = 1.1;
+const CAMERA_MIN_SCALE: f32 = 0.05;
+const CAMERA_MAX_SCALE: f32 = 20.;
+// Fraction of the remaining distance covered per second when following or framing
+const CAMERA_SMOOTHING: f32 = 5.;
+const CAMERA_FRAME_MARGIN: f32 = 1.1;
+
+// With the select tool, the left mouse button picks the nearest boid within this distance.
+const SELECT_RADIUS: f32 = 20.;
+
+// Parameter panel, toggled with `P`.
+const PANEL_WIDTH: f32 = 320.;
+const PANEL_FONT_SIZE: f32 = 12.;
+
+// Colour modes, cycled with `V` (and palettes with `Shift+V`). Neighbour counts and
+// accelerations at or above these values get the top of the palette.
+const COLOR_MAX_NEIGHBORS: f32 = 30.;
+const COLOR_MAX_ACCELERATION: f32 = 2000.;
+const PALETTES: [Palette; 4] = [
+    Palette::VIRIDIS,
+    Palette::MAGMA,
+    Palette::COOL_WARM,
+    Palette::GRAYSCALE,
+];
+const LEGEND_SWATCHES: usize = 32;
+// Live plots of the flock's metrics, toggled with `M`. Each chart shows the last PLOT_SAMPLES
+// ticks.
+const PLOT_SAMPLES: usize = 600;
+const PLOT_WIDTH: f32 = 240.;
+const PLOT_HEIGHT: f32 = 48.;
+// Spatial and velocity correlations, plotted with `K` and written with `--correlations`. Pairs
+// are binned up to CORRELATION_MAX_RADIUS apart, every CORRELATION_EVERY ticks since the pair
+// search is slow.
+const CORRELATION_MAX_RADIUS: f32 = 200.;
+const CORRELATION_BINS: usize = 40;
+const CORRELATION_EVERY: u64 = 30;
+// Clusters are boids linked by chains of neighbours within the alignment radius. Each is tracked
+// as a flock from frame to frame, and splits and merges are reported between flocks of at least
+// FLOCK_EVENT_MIN_SIZE boids. In the cluster colour mode every flock keeps its own colour and
+// lone boids are drawn in LONER_COLOR.
+const FLOCK_EVENT_MIN_SIZE: usize = 5;
+const LONER_COLOR: Color = Color::srgb(0.4, 0.4, 0.4);
+
+// Trails, cycled with `T` (off, all boids, the selected boid, tagged boids). `Shift+T` tags or
+// untags the selected boid. A trail holds TRAIL_LENGTH positions sampled every TRAIL_INTERVAL
+// seconds, fading out from TRAIL_ALPHA.
+const TRAIL_MODE: TrailMode = TrailMode::Off;
+const TRAIL_LENGTH: usize = 30;
+const TRAIL_INTERVAL: f32 = 0.05;
+const TRAIL_ALPHA: f32 = 0.5;
+
+const SEED: u64 = 0;
+
+// `F5` saves the whole simulation here and `F9` restores it.
+const SNAPSHOT_PATH: &str = "snapshots/snapshot.ron";
+
+// Trajectory recording, enabled with `--record <path>`: one row per boid every RECORD_EVERY
+// ticks for about RECORD_SAMPLE of the boids, starting a new file every RECORD_ROTATE_TICKS.
+// Each can be overridden on the command line.
+const RECORD_EVERY: u64 = 1;
+const RECORD_SAMPLE: f32 = 1.;
+const RECORD_ROTATE_TICKS: Option<u64> = None;
+
+// Seconds simulated per tick with `--headless`, which runs as fast as it can.
+const HEADLESS_TIMESTEP: f64 = 1. / 60.;
+
+// Replay, enabled with `--replay <path>`: recorded ticks are played back at REPLAY_TICK_RATE
+// ticks per second times the playback speed. `←`/`→` jump REPLAY_SCRUB_TICKS back or forward.
+const REPLAY_TICK_RATE: f64 = 60.;
+const REPLAY_SCRUB_TICKS: f64 = 60.;
+const REPLAY_MIN_SPEED: f64 = 1. / 16.;
+const REPLAY_MAX_SPEED: f64 = 64.;
+
+// Voxel cell size as a fraction of the alignment radius. Boids only search the 3x3 block of
+// cells around them, so 3 cells should span the alignment diameter. Toggle the grid overlay
+// with `G` to see which cells are searched for the selected boid.
+const VOXEL_CELL_FACTOR: f32 = 2. / 3.;
+
+// Debug layers: `G` toggles the voxel grid, while `R` (perception radii), `X` (steering
+// arrows), `N` (neighbour links) and `I` (entity IDs) cycle between off, the selected boid and
+// all boids. Rule contributions are velocity changes per frame, so their arrows are drawn much
+// longer than the velocity itself. At most DEBUG_ID_LIMIT IDs in view are labelled.
+const STEERING_ARROW_SCALE: f32 = 20.;
+const VELOCITY_ARROW_SCALE: f32 = 0.1;
+const DEBUG_ID_LIMIT: usize = 200;
 
 #[derive(Component, PartialEq)]
 struct Boid {
@@ -26,311 +190,3975 @@ struct Boid {
     align_radius: f32,
     separation_accumulator: Vec3,
     alignment_accumulator: Vec3,
+    alignment_weight: f32,
     position_accumulator: Vec3,
     n_neighbors: usize,
 }
 
-#[derive(Component)]
-struct Velocity(Vec3);
+/// Id of a boid, counted from 0 in spawn order and kept in snapshots. Unlike its entity, it
+/// isn't reused after the boid is despawned, so it identifies the boid in recordings.
+#[derive(Component, Clone, Copy, PartialEq, Debug)]
+struct BoidId(u64);
+
+/// The id of the next boid to be spawned.
+#[derive(Resource, Default)]
+struct NextBoidId(u64);
+
+impl NextBoidId {
+    fn next(&mut self) -> BoidId {
+        self.0 += 1;
+        BoidId(self.0 - 1)
+    }
+}
+
+#[derive(Component)]
+struct Velocity(Vec3);
+
+/// Fill colour of a boid in the flock mesh.
+#[derive(Component)]
+struct BoidColor(Color);
+
+/// Change in velocity per second over the last frame.
+#[derive(Component)]
+struct Acceleration {
+    value: Vec3,
+    last_velocity: Vec3,
+}
+
+/// Velocity changes applied by each flocking rule in the last step, kept for inspection.
+#[derive(Component, Default)]
+struct Steering {
+    separation: Vec3,
+    alignment: Vec3,
+    cohesion: Vec3,
+    /// Turn away from the window edge
+    boundary: Vec3,
+    n_neighbors: usize,
+}
+
+/// Simulation parameters, initialised from the constants above and tuned at runtime from the
+/// parameter panel. Fields missing from older snapshots are filled in from `Params::default`.
+#[derive(Resource, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Params {
+    boid_number: usize,
+    max_speed: f32,
+    min_speed: f32,
+    separation_factor: f32,
+    alignment_factor: f32,
+    cohesion_factor: f32,
+    turn_factor: f32,
+    separation_radius: f32,
+    alignment_radius: f32,
+    leader_fraction: f32,
+    leader_goal_factor: f32,
+    leader_alignment_weight: f32,
+    player_leaders: PlayerLeaders,
+    brush_heading: BrushHeading,
+    wander_mode: WanderMode,
+    wander_noise: f32,
+    boundary: BoundaryMode,
+    trail_length: usize,
+    trail_interval: f32,
+    cell_size_factor: f32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Self {
+            boid_number: BOID_NUMBER,
+            max_speed: BOID_MAX_SPEED,
+            min_speed: BOID_MIN_SPEED,
+            separation_factor: BOID_SEPARATION_FACTOR,
+            alignment_factor: BOID_ALIGNMENT_FACTOR,
+            cohesion_factor: BOID_COHESION_FACTOR,
+            turn_factor: BOID_TURN_FACTOR,
+            separation_radius: BOID_SEPARATION_RADIUS,
+            alignment_radius: BOID_ALIGNMENT_RADIUS,
+            leader_fraction: LEADER_FRACTION,
+            leader_goal_factor: LEADER_GOAL_FACTOR,
+            leader_alignment_weight: LEADER_ALIGNMENT_WEIGHT,
+            player_leaders: LEADER_PLAYER_LEADERS,
+            brush_heading: BRUSH_HEADING,
+            wander_mode: WANDER_MODE,
+            wander_noise: WANDER_NOISE,
+            boundary: BoundaryMode::Avoid,
+            trail_length: TRAIL_LENGTH,
+            trail_interval: TRAIL_INTERVAL,
+            cell_size_factor: VOXEL_CELL_FACTOR,
+        }
+    }
+}
+
+/// Which leaders are turned with the arrow keys instead of keeping their heading.
+#[derive(Default, Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+enum PlayerLeaders {
+    None,
+    /// The first leader
+    #[default]
+    One,
+    All,
+}
+
+impl PlayerLeaders {
+    fn next(self) -> Self {
+        match self {
+            PlayerLeaders::None => PlayerLeaders::One,
+            PlayerLeaders::One => PlayerLeaders::All,
+            PlayerLeaders::All => PlayerLeaders::None,
+        }
+    }
+
+    fn includes(self, leader_index: usize) -> bool {
+        match self {
+            PlayerLeaders::None => false,
+            PlayerLeaders::One => leader_index == 0,
+            PlayerLeaders::All => true,
+        }
+    }
+}
+
+/// What happens at the edge of the window.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+enum BoundaryMode {
+    /// Turn back before reaching the edge
+    Avoid,
+    /// Wrap around to the opposite edge
+    Periodic,
+}
+
+/// What boid colours encode.
+#[derive(Resource, Default, Clone, Copy, PartialEq, Debug)]
+enum ColorMode {
+    /// White, with leaders highlighted
+    #[default]
+    Uniform,
+    /// Direction of travel on a hue wheel
+    Heading,
+    Speed,
+    /// Neighbours within the alignment radius
+    Neighbors,
+    /// Leaders and followers
+    Species,
+    /// Magnitude of the change in velocity
+    Acceleration,
+    /// Connected group, keeping its colour as it moves
+    Cluster,
+}
+
+impl ColorMode {
+    fn next(self) -> Self {
+        match self {
+            ColorMode::Uniform => ColorMode::Heading,
+            ColorMode::Heading => ColorMode::Speed,
+            ColorMode::Speed => ColorMode::Neighbors,
+            ColorMode::Neighbors => ColorMode::Species,
+            ColorMode::Species => ColorMode::Acceleration,
+            ColorMode::Acceleration => ColorMode::Cluster,
+            ColorMode::Cluster => ColorMode::Uniform,
+        }
+    }
+
+    /// Colour at `t` along this mode's scale, from 0 to 1.
+    fn scale(self, palette: Palette, t: f32) -> Color {
+        match self {
+            ColorMode::Uniform | ColorMode::Cluster => Color::WHITE,
+            ColorMode::Heading => Palette::HUE.sample(t),
+            ColorMode::Species => categorical((t >= 0.5) as usize),
+            ColorMode::Speed | ColorMode::Neighbors | ColorMode::Acceleration => palette.sample(t),
+        }
+    }
+
+    /// Legend title and the labels at either end of the scale, if the mode has one.
+    fn legend(self, params: &Params) -> Option<(&'static str, String, String)> {
+        match self {
+            ColorMode::Uniform | ColorMode::Cluster => None,
+            ColorMode::Heading => Some(("Heading", "0°".into(), "360°".into())),
+            ColorMode::Speed => Some((
+                "Speed",
+                format!("{:.0}", params.min_speed),
+                format!("{:.0}", params.max_speed),
+            )),
+            ColorMode::Neighbors => {
+                Some(("Neighbours", "0".into(), format!("{COLOR_MAX_NEIGHBORS}+")))
+            }
+            ColorMode::Species => Some(("Species", "Follower".into(), "Leader".into())),
+            ColorMode::Acceleration => Some((
+                "Acceleration",
+                "0".into(),
+                format!("{COLOR_MAX_ACCELERATION}+"),
+            )),
+        }
+    }
+}
+
+/// Index into `PALETTES` used by the scalar colour modes.
+#[derive(Resource, Default)]
+struct ColorPalette(usize);
+
+/// Sent to despawn the flock and spawn `Params::boid_number` new boids.
+#[derive(Event)]
+struct Respawn;
+
+/// Wander state: the angle on the wander circle, or the turning rate for Ornstein-Uhlenbeck noise.
+#[derive(Component, Default)]
+struct Wander(f32);
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+enum WanderMode {
+    Off,
+    Circle,
+    OrnsteinUhlenbeck,
+}
+
+/// Meshes the trails are drawn with.
+#[derive(Resource)]
+struct BoidAssets {
+    /// Mesh rebuilt from [`TrailMesh`] every frame
+    trails: Handle<Mesh>,
+}
+
+/// Cursor (or touch) position in world coordinates, with the primary and secondary buttons.
+/// A second finger counts as the secondary button.
+#[derive(Resource, Default)]
+struct Pointer {
+    position: Option<Vec2>,
+    previous: Option<Vec2>,
+    primary: bool,
+    secondary: bool,
+    /// The pointer is over a UI panel, so it should not act on the world.
+    over_ui: bool,
+    /// The finger the pointer follows, from when it touches down until it's lifted
+    touch: Option<u64>,
+}
+
+/// What the pointer buttons do, selected with the number keys.
+#[derive(Resource, Default, Clone, Copy, PartialEq, Debug)]
+enum PointerTool {
+    #[default]
+    Attract,
+    Brush,
+    Select,
+}
+
+/// Initial heading of painted boids.
+#[derive(Default, Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+enum BrushHeading {
+    Random,
+    /// Away from the centre of the brush
+    Outward,
+    /// Along the direction the brush is moving
+    #[default]
+    Stroke,
+}
+
+impl BrushHeading {
+    fn next(self) -> Self {
+        match self {
+            BrushHeading::Random => BrushHeading::Outward,
+            BrushHeading::Outward => BrushHeading::Stroke,
+            BrushHeading::Stroke => BrushHeading::Random,
+        }
+    }
+}
+
+/// Fraction of a boid left over from painting in previous frames.
+#[derive(Resource, Default)]
+struct BrushCarry(f32);
+
+/// Marks the boid picked for inspection.
+#[derive(Component)]
+struct Selected;
+
+/// The boid's cluster, updated every frame.
+#[derive(Component, Default)]
+struct ClusterId {
+    /// Index into `Clusters`, which changes as clusters grow and shrink
+    index: usize,
+    flock: FlockId,
+}
+
+/// Connected groups of boids from the last frame, and the flock each is tracked as.
+#[derive(Resource, Default)]
+struct Clusters {
+    clustering: Clustering,
+    flocks: Vec<FlockId>,
+}
+
+/// Marks a boid to leave a trail in `TrailMode::Tagged`.
+#[derive(Component)]
+struct Tagged;
+
+/// Which boids leave trails.
+#[derive(Resource, Clone, Copy, PartialEq, Debug)]
+enum TrailMode {
+    Off,
+    All,
+    Selected,
+    Tagged,
+}
+
+impl TrailMode {
+    fn next(self) -> Self {
+        match self {
+            TrailMode::Off => TrailMode::All,
+            TrailMode::All => TrailMode::Selected,
+            TrailMode::Selected => TrailMode::Tagged,
+            TrailMode::Tagged => TrailMode::Off,
+        }
+    }
+}
+
+/// Debug overlays drawn with gizmos, toggled at runtime.
+#[derive(Resource, Default)]
+struct DebugLayers {
+    /// Occupied voxel cells, shaded by occupancy, and the cells searched for the selected boid
+    grid: bool,
+    /// Separation and alignment radii
+    radii: DebugScope,
+    /// Arrows for each steering rule and the resulting velocity
+    vectors: DebugScope,
+    /// Lines to the neighbours within the alignment radius
+    links: DebugScope,
+    /// Entity IDs
+    ids: DebugScope,
+}
+
+/// Which boids a debug layer is drawn for.
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+enum DebugScope {
+    #[default]
+    Off,
+    Selected,
+    All,
+}
+
+impl DebugScope {
+    fn next(self) -> Self {
+        match self {
+            DebugScope::Off => DebugScope::Selected,
+            DebugScope::Selected => DebugScope::All,
+            DebugScope::All => DebugScope::Off,
+        }
+    }
+
+    fn includes(self, selected: bool) -> bool {
+        match self {
+            DebugScope::Off => false,
+            DebugScope::Selected => selected,
+            DebugScope::All => true,
+        }
+    }
+}
+
+/// Pooled text label for the entity ID layer.
+#[derive(Component)]
+struct DebugLabel;
+
+/// Seconds since trails were last sampled.
+#[derive(Resource, Default)]
+struct TrailClock(f32);
+
+/// Text panel describing the selected boid.
+#[derive(Component)]
+struct Inspector;
+
+/// Root node of the colour legend.
+#[derive(Component)]
+struct Legend;
+
+/// One segment of the legend's colour bar.
+#[derive(Component)]
+struct LegendSwatch(usize);
+
+#[derive(Component, Clone, Copy)]
+enum LegendText {
+    Title,
+    Low,
+    High,
+}
+
+/// Root node of the metrics plots.
+#[derive(Component)]
+struct MetricsPanel;
+
+/// Area of the metrics panel a chart is drawn over with gizmos, and the chart's label.
+#[derive(Component, Clone, Copy)]
+enum MetricsChart {
+    Polarization,
+    Clusters,
+    Fps,
+}
+
+#[derive(Component)]
+struct MetricsChartLabel(MetricsChart);
+
+/// Root node of the correlation plots.
+#[derive(Component)]
+struct CorrelationPanel;
+
+/// Area of the correlation panel a chart is drawn over, and the chart's label.
+#[derive(Component, Clone, Copy)]
+enum CorrelationChart {
+    /// g(r)
+    Pair,
+    /// C(r)
+    Velocity,
+}
+
+#[derive(Component)]
+struct CorrelationChartLabel(CorrelationChart);
+
+impl CorrelationChart {
+    const ALL: [CorrelationChart; 2] = [CorrelationChart::Pair, CorrelationChart::Velocity];
+
+    fn values(self, correlations: &Correlations) -> Vec<f32> {
+        match self {
+            CorrelationChart::Pair => correlations.pair_correlation(),
+            CorrelationChart::Velocity => correlations.velocity_correlation(),
+        }
+    }
+
+    /// Values at the bottom and top of the chart.
+    fn range(self, values: &[f32]) -> (f32, f32) {
+        match self {
+            CorrelationChart::Pair => (
+                0.,
+                values
+                    .iter()
+                    .copied()
+                    .filter(|value| !value.is_nan())
+                    .fold(1., f32::max),
+            ),
+            CorrelationChart::Velocity => (-1., 1.),
+        }
+    }
+
+    fn label(self, correlations: &Correlations) -> String {
+        match self {
+            CorrelationChart::Pair => format!(
+                "g(r)  0 to {:.0} px, {} frames",
+                correlations.max_radius(),
+                correlations.frames
+            ),
+            CorrelationChart::Velocity => match correlations.correlation_length() {
+                Some(length) => format!("C(r)  length {length:.0} px"),
+                None => "C(r)  length -".to_string(),
+            },
+        }
+    }
+
+    fn color(self) -> Color {
+        categorical(self as usize + MetricsChart::ALL.len())
+    }
+}
+
+impl MetricsChart {
+    const ALL: [MetricsChart; 3] = [
+        MetricsChart::Polarization,
+        MetricsChart::Clusters,
+        MetricsChart::Fps,
+    ];
+
+    fn series(self, plot: &MetricsPlot) -> &RollingSeries {
+        match self {
+            MetricsChart::Polarization => &plot.polarization,
+            MetricsChart::Clusters => &plot.clusters,
+            MetricsChart::Fps => &plot.fps,
+        }
+    }
+
+    /// Values at the bottom and top of the chart.
+    fn range(self, plot: &MetricsPlot) -> (f32, f32) {
+        match self {
+            MetricsChart::Polarization => (0., 1.),
+            _ => (0., self.series(plot).max().unwrap_or(1.).max(1.)),
+        }
+    }
+
+    fn label(self, value: f32) -> String {
+        match self {
+            MetricsChart::Polarization => format!("Polarization  {value:.2}"),
+            MetricsChart::Clusters => format!("Clusters  {value:.0}"),
+            MetricsChart::Fps => format!("FPS  {value:.0}"),
+        }
+    }
+
+    fn color(self) -> Color {
+        categorical(self as usize)
+    }
+}
+
+/// Root node of the parameter panel.
+#[derive(Component)]
+struct Panel;
+
+/// Slider track for `SLIDERS[index]`.
+#[derive(Component)]
+struct Slider(usize);
+
+/// Filled part of a slider track.
+#[derive(Component)]
+struct SliderFill(usize);
+
+/// Value label of a slider.
+#[derive(Component)]
+struct SliderValue(usize);
+
+struct SliderSpec {
+    label: &'static str,
+    min: f32,
+    max: f32,
+    precision: usize,
+    get: fn(&Params) -> f32,
+    set: fn(&mut Params, f32),
+}
+
+const SLIDERS: &[SliderSpec] = &[
+    SliderSpec {
+        label: "Boids (respawn)",
+        min: 0.,
+        max: 50000.,
+        precision: 0,
+        get: |p| p.boid_number as f32,
+        set: |p, v| p.boid_number = (v / 100.).round() as usize * 100,
+    },
+    SliderSpec {
+        label: "Max speed",
+        min: 50.,
+        max: 1500.,
+        precision: 0,
+        get: |p| p.max_speed,
+        set: |p, v| p.max_speed = v.max(p.min_speed),
+    },
+    SliderSpec {
+        label: "Min speed",
+        min: 0.,
+        max: 500.,
+        precision: 0,
+        get: |p| p.min_speed,
+        set: |p, v| p.min_speed = v.min(p.max_speed),
+    },
+    SliderSpec {
+        label: "Separation",
+        min: 0.,
+        max: 0.2,
+        precision: 3,
+        get: |p| p.separation_factor,
+        set: |p, v| p.separation_factor = v,
+    },
+    SliderSpec {
+        label: "Alignment",
+        min: 0.,
+        max: 0.2,
+        precision: 3,
+        get: |p| p.alignment_factor,
+        set: |p, v| p.alignment_factor = v,
+    },
+    SliderSpec {
+        label: "Cohesion",
+        min: 0.,
+        max: 0.02,
+        precision: 4,
+        get: |p| p.cohesion_factor,
+        set: |p, v| p.cohesion_factor = v,
+    },
+    SliderSpec {
+        label: "Separation radius",
+        min: 1.,
+        max: 50.,
+        precision: 1,
+        get: |p| p.separation_radius,
+        set: |p, v| p.separation_radius = v.min(p.alignment_radius),
+    },
+    SliderSpec {
+        label: "Alignment radius",
+        min: 5.,
+        max: 150.,
+        precision: 1,
+        get: |p| p.alignment_radius,
+        set: |p, v| p.alignment_radius = v.max(p.separation_radius),
+    },
+    SliderSpec {
+        label: "Boundary turn",
+        min: 0.,
+        max: 30.,
+        precision: 1,
+        get: |p| p.turn_factor,
+        set: |p, v| p.turn_factor = v,
+    },
+    SliderSpec {
+        label: "Leaders (respawn)",
+        min: 0.,
+        max: 1.,
+        precision: 2,
+        get: |p| p.leader_fraction,
+        set: |p, v| p.leader_fraction = v,
+    },
+    SliderSpec {
+        label: "Leader goal",
+        min: 0.,
+        max: 0.2,
+        precision: 3,
+        get: |p| p.leader_goal_factor,
+        set: |p, v| p.leader_goal_factor = v,
+    },
+    SliderSpec {
+        label: "Leader weight",
+        min: 1.,
+        max: 50.,
+        precision: 1,
+        get: |p| p.leader_alignment_weight,
+        set: |p, v| p.leader_alignment_weight = v,
+    },
+    SliderSpec {
+        label: "Wander noise",
+        min: 0.,
+        max: 10.,
+        precision: 2,
+        get: |p| p.wander_noise,
+        set: |p, v| p.wander_noise = v,
+    },
+    SliderSpec {
+        label: "Trail length",
+        min: 0.,
+        max: 200.,
+        precision: 0,
+        get: |p| p.trail_length as f32,
+        set: |p, v| p.trail_length = v.round() as usize,
+    },
+    SliderSpec {
+        label: "Trail interval (s)",
+        min: 0.01,
+        max: 0.5,
+        precision: 2,
+        get: |p| p.trail_interval,
+        set: |p, v| p.trail_interval = v,
+    },
+    SliderSpec {
+        label: "Cell size (× radius)",
+        min: 0.2,
+        max: 2.,
+        precision: 2,
+        get: |p| p.cell_size_factor,
+        set: |p, v| p.cell_size_factor = v,
+    },
+];
+
+/// Panel buttons, labelled with the current state of what they toggle.
+#[derive(Component, Clone, Copy)]
+enum PanelButton {
+    Boundary,
+    Wander,
+    PlayerLeaders,
+    BrushHeading,
+    Colors,
+    Palette,
+    Trails,
+    FlowArrows,
+    Grid,
+    Radii,
+    SteeringArrows,
+    NeighborLinks,
+    Ids,
+    Pause,
+    Reset,
+    Respawn,
+}
+
+/// How the camera moves: freely under user control, tracking the selected boid, or fitting
+/// the whole flock in view.
+#[derive(Resource, Default, Clone, Copy, PartialEq, Debug)]
+enum CameraMode {
+    #[default]
+    Free,
+    Follow,
+    Frame,
+}
+
+/// Point under the cursor or touch that pulls boids in (positive strength) or pushes them away.
+#[derive(Resource, Default)]
+struct Attractor {
+    position: Option<Vec2>,
+    strength: f32,
+}
+
+/// Command line options.
+#[derive(Resource, Clone, Debug)]
+struct Cli {
+    /// Run without a window or renderer
+    headless: bool,
+    /// Exit after this many ticks
+    ticks: Option<u64>,
+    /// Record trajectories to this file, as NDJSON for `.ndjson`/`.jsonl` and CSV otherwise
+    record: Option<PathBuf>,
+    record_every: u64,
+    record_sample: f32,
+    record_rotate: Option<u64>,
+    /// Play back a recording instead of simulating
+    replay: Option<PathBuf>,
+    /// Write the flock's metrics every tick to this file, in the same formats as trajectories
+    metrics: Option<PathBuf>,
+    /// Write g(r) and C(r), averaged over the run, to this CSV file on exit
+    correlations: Option<PathBuf>,
+}
+
+const USAGE: &str = "Usage: bevy_boids [--headless] [--ticks N] [--record PATH] \
+[--record-every N] [--record-sample FRACTION] [--record-rotate TICKS] [--replay PATH] \
+[--metrics PATH] [--correlations PATH]";
+
+impl Cli {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut cli = Cli {
+            headless: false,
+            ticks: None,
+            record: None,
+            record_every: RECORD_EVERY,
+            record_sample: RECORD_SAMPLE,
+            record_rotate: RECORD_ROTATE_TICKS,
+            replay: None,
+            metrics: None,
+            correlations: None,
+        };
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+            match arg.as_str() {
+                "--headless" => cli.headless = true,
+                "--ticks" => cli.ticks = Some(parse_arg(&arg, value()?)?),
+                "--record" => cli.record = Some(value()?.into()),
+                "--record-every" => cli.record_every = parse_arg::<u64>(&arg, value()?)?.max(1),
+                "--record-sample" => cli.record_sample = parse_arg(&arg, value()?)?,
+                "--record-rotate" => cli.record_rotate = Some(parse_arg(&arg, value()?)?),
+                "--replay" => cli.replay = Some(value()?.into()),
+                "--metrics" => cli.metrics = Some(value()?.into()),
+                "--correlations" => cli.correlations = Some(value()?.into()),
+                _ => return Err(format!("unknown argument {arg}")),
+            }
+        }
+        for path in cli.record.iter().chain(&cli.replay).chain(&cli.metrics) {
+            if let Some(feature) = TrajectoryFormat::from_path(path).missing_feature() {
+                return Err(format!(
+                    "{} needs the `{feature}` feature: cargo run --features {feature}",
+                    path.display()
+                ));
+            }
+        }
+        Ok(cli)
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(arg: &str, value: String) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {value} for {arg}"))
+}
+
+/// Number of simulation steps taken, not counting paused frames.
+#[derive(Resource, Default)]
+struct SimTick(u64);
+
+/// Writes boid trajectories while recording.
+#[derive(Resource)]
+struct Recorder {
+    writer: TrajectoryWriter,
+    every: u64,
+    sample: f32,
+}
+
+/// Writes the flock's metrics with `--metrics`.
+#[derive(Resource)]
+struct MetricsLog(MetricsWriter);
+
+/// Metrics of the current tick, filled in by `measure_flock` and `find_clusters`.
+#[derive(Resource, Default)]
+struct LatestMetrics(MetricsRow);
+
+/// Recent metrics shown in the plots.
+#[derive(Resource)]
+struct MetricsPlot {
+    polarization: RollingSeries,
+    clusters: RollingSeries,
+    fps: RollingSeries,
+}
+
+impl Default for MetricsPlot {
+    fn default() -> Self {
+        Self {
+            polarization: RollingSeries::new(PLOT_SAMPLES),
+            clusters: RollingSeries::new(PLOT_SAMPLES),
+            fps: RollingSeries::new(PLOT_SAMPLES),
+        }
+    }
+}
+
+/// Correlations averaged over the run for `--correlations`, and since the plots were shown.
+#[derive(Resource)]
+struct CorrelationAnalysis {
+    run: Option<Correlations>,
+    plot: Option<Correlations>,
+}
+
+/// Playback state with `--replay`. Boids are spawned and moved to match the recorded frame at
+/// `tick` instead of being simulated.
+#[derive(Resource)]
+struct Replay {
+    recording: Recording,
+    /// Playback position, between recorded ticks while playing slowly
+    tick: f64,
+    speed: f64,
+    /// Tick of the frame the boids currently show
+    shown: Option<u64>,
+    /// Boid entity for each recorded id
+    entities: HashMap<u64, Entity>,
+}
+
+/// Timeline at the bottom of the window while replaying, dragged to scrub.
+#[derive(Component)]
+struct ReplayBar;
+
+#[derive(Component)]
+struct ReplayBarFill;
+
+#[derive(Component)]
+struct ReplayLabel;
+
+/// Seeded random number generator shared by the simulation, so runs are reproducible.
+#[derive(Resource)]
+struct SimRng(ChaCha8Rng);
+
+/// An informed boid that steers towards its own preferred heading while still flocking.
+#[derive(Component)]
+struct Leader {
+    heading: Vec3,
+}
+
+/// Leader whose heading is turned with the arrow keys.
+#[derive(Component)]
+struct PlayerControlled;
+
+/// Tracks how long the flock takes to follow the leaders after their heading changes.
+#[derive(Resource, Default)]
+struct Leadership {
+    heading_changed_at: f32,
+    following: bool,
+}
+
+/// Flow grid loaded from `FLOW_GRID_PATH`.
+#[derive(Resource)]
+struct FlowGridSource(Handle<FlowGrid>);
+
+/// Whether the flow field is drawn as an arrow overlay.
+#[derive(Resource, Default)]
+struct FlowOverlay(bool);
+
+const LEADERSHIP_ACCURACY: DiagnosticPath = DiagnosticPath::const_new("leadership_accuracy");
+const POLARIZATION: DiagnosticPath = DiagnosticPath::const_new("flock/polarization");
+const MILLING: DiagnosticPath = DiagnosticPath::const_new("flock/milling");
+const NEAREST_NEIGHBOR_DISTANCE: DiagnosticPath =
+    DiagnosticPath::const_new("flock/nearest_neighbor_distance");
+const GROUP_RADIUS: DiagnosticPath = DiagnosticPath::const_new("flock/group_radius");
+const MEAN_SPEED: DiagnosticPath = DiagnosticPath::const_new("flock/mean_speed");
+const CLUSTER_COUNT: DiagnosticPath = DiagnosticPath::const_new("flock/clusters");
+const LARGEST_CLUSTER: DiagnosticPath = DiagnosticPath::const_new("flock/largest_cluster");
+const CORRELATION_LENGTH: DiagnosticPath = DiagnosticPath::const_new("flock/correlation_length");
+
+fn main() {
+    let cli = match Cli::parse(std::env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(err) => {
+            eprintln!("{err}\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    // Load the recording before opening a window, so a bad path fails straight away
+    let replay = cli.replay.as_ref().map(|path| {
+        let recording = Recording::load(path).unwrap_or_else(|err| {
+            eprintln!("Could not load replay: {err}");
+            std::process::exit(1);
+        });
+        println!(
+            "Replaying {} frames, ticks {} to {}",
+            recording.len(),
+            recording.first_tick(),
+            recording.last_tick()
+        );
+        Replay {
+            tick: recording.first_tick() as f64,
+            recording,
+            speed: 1.,
+            shown: None,
+            entities: HashMap::new(),
+        }
+    });
+
+    let default_plugins = DefaultPlugins.set(AssetPlugin {
+        // Wasm builds will check for meta files (that don't exist) if this isn't set.
+        // This causes errors and even panics in web builds on itch.
+        // See https://github.com/bevyengine/bevy_github_ci_template/issues/48.
+        meta_check: AssetMetaCheck::Never,
+        ..default()
+    });
+
+    let mut app = App::new();
+    if cli.headless {
+        app.add_plugins(
+            default_plugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    ..default()
+                })
+                .set(RenderPlugin {
+                    render_creation: WgpuSettings {
+                        backends: None,
+                        ..default()
+                    }
+                    .into(),
+                    ..default()
+                })
+                .disable::<WinitPlugin>(),
+        )
+        .add_plugins(ScheduleRunnerPlugin::run_loop(Duration::ZERO))
+        // Fixed steps, independent of how fast the machine runs them
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            HEADLESS_TIMESTEP,
+        )));
+    } else {
+        app.add_plugins(default_plugins);
+    }
+    if let Some(replay) = replay {
+        app.insert_resource(replay);
+    }
+    if let Some(path) = &cli.metrics {
+        match MetricsWriter::create(path, TrajectoryFormat::from_path(path), run_metadata(&cli)) {
+            Ok(writer) => app.insert_resource(MetricsLog(writer)),
+            Err(err) => {
+                eprintln!("Could not create {}: {err}", path.display());
+                std::process::exit(1);
+            }
+        };
+    }
+    if let Some(path) = &cli.record {
+        app.insert_resource(Recorder {
+            writer: TrajectoryWriter::new(
+                path,
+                TrajectoryFormat::from_path(path),
+                cli.record_rotate,
+            )
+            .with_metadata(run_metadata(&cli)),
+            every: cli.record_every,
+            sample: cli.record_sample,
+        });
+    }
+
+    app.add_plugins((FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin::default()))
+        .add_plugins((FlowFieldPlugin, FlockRenderPlugin))
+        .register_diagnostic(Diagnostic::new(LEADERSHIP_ACCURACY))
+        .register_diagnostic(Diagnostic::new(POLARIZATION))
+        .register_diagnostic(Diagnostic::new(MILLING))
+        .register_diagnostic(Diagnostic::new(NEAREST_NEIGHBOR_DISTANCE).with_suffix(" px"))
+        .register_diagnostic(Diagnostic::new(GROUP_RADIUS).with_suffix(" px"))
+        .register_diagnostic(Diagnostic::new(MEAN_SPEED).with_suffix(" px/s"))
+        .register_diagnostic(Diagnostic::new(CLUSTER_COUNT))
+        .register_diagnostic(Diagnostic::new(LARGEST_CLUSTER).with_suffix(" boids"))
+        .register_diagnostic(Diagnostic::new(CORRELATION_LENGTH).with_suffix(" px"))
+        .insert_resource(CorrelationAnalysis {
+            run: cli
+                .correlations
+                .as_ref()
+                .map(|_| Correlations::new(CORRELATION_MAX_RADIUS, CORRELATION_BINS)),
+            plot: None,
+        })
+        .insert_resource(cli)
+        .init_resource::<SimTick>()
+        .init_resource::<NextBoidId>()
+        .init_resource::<Leadership>()
+        .insert_resource(SimRng(ChaCha8Rng::seed_from_u64(SEED)))
+        .init_resource::<FlowOverlay>()
+        .init_resource::<Attractor>()
+        .init_resource::<Pointer>()
+        .init_resource::<PointerTool>()
+        .init_resource::<BrushCarry>()
+        .init_resource::<CameraMode>()
+        .init_resource::<Params>()
+        .init_resource::<ColorMode>()
+        .init_resource::<ColorPalette>()
+        .init_resource::<Clusters>()
+        .init_resource::<LatestMetrics>()
+        .init_resource::<MetricsPlot>()
+        .insert_resource(ClusterTracker::new(FLOCK_EVENT_MIN_SIZE))
+        .insert_resource(TRAIL_MODE)
+        .init_resource::<TrailClock>()
+        .init_resource::<TrailMesh>()
+        .init_resource::<DebugLayers>()
+        .add_event::<Respawn>()
+        .add_event::<FlockSplit>()
+        .add_event::<FlockMerged>()
+        .add_systems(
+            Startup,
+            (
+                setup,
+                spawn_boids,
+                spawn_panel,
+                spawn_legend,
+                spawn_metrics_panel,
+                spawn_correlation_panel,
+                spawn_replay_bar.run_if(resource_exists::<Replay>),
+            )
+                .chain(),
+        )
+        // .add_systems(Update, (avoid_boundary, move_boids).chain())
+        .add_systems(
+            Update,
+            (
+                (advance_tick, respawn_boids, load_snapshot)
+                    .chain()
+                    .run_if(not(resource_exists::<Replay>)),
+                apply_params,
+                apply_player_leaders.run_if(not(resource_exists::<Replay>)),
+                steer_player_leader.run_if(not(resource_exists::<Replay>)),
+                advance_flow_field,
+                update_pointer,
+                play_replay.run_if(resource_exists::<Replay>),
+                (
+                    pointer_attractor,
+                    brush,
+                    boids_behavior,
+                    wander,
+                    attract_boids,
+                    avoid_boundary,
+                    move_boids,
+                    periodic_boundary,
+                    measure_leadership,
+                )
+                    .chain()
+                    .run_if(not(resource_exists::<Replay>)),
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            (
+                color_boids,
+                update_flock_instances,
+                record_trails,
+                update_trail_mesh,
+            )
+                .chain()
+                .after(find_clusters),
+        )
+        .add_systems(Update, save_snapshot.after(periodic_boundary))
+        .add_systems(
+            Update,
+            (
+                (
+                    measure_flock.run_if(not_paused),
+                    (find_clusters, log_flock_events).chain(),
+                    measure_correlations.run_if(not_paused),
+                ),
+                record_metrics.run_if(not_paused),
+            )
+                .chain()
+                .after(periodic_boundary)
+                .after(play_replay),
+        )
+        .add_systems(
+            Update,
+            (
+                toggle_metrics_panel,
+                update_metrics_panel,
+                draw_metrics_plot,
+                toggle_correlation_panel,
+                update_correlation_panel,
+                draw_correlation_plot,
+            )
+                .chain()
+                .after(record_metrics),
+        )
+        .add_systems(
+            Update,
+            (
+                record_trajectories
+                    .run_if(not(resource_exists::<Replay>))
+                    .run_if(not_paused),
+                exit_after_ticks,
+            )
+                .chain()
+                .after(periodic_boundary)
+                .after(play_replay),
+        )
+        .add_systems(Last, flush_recorder)
+        .add_systems(
+            Update,
+            (replay_controls, scrub_replay_bar, update_replay_bar)
+                .chain()
+                .before(play_replay)
+                .run_if(resource_exists::<Replay>),
+        )
+        .add_systems(Update, (cycle_color_mode, update_legend).chain())
+        .add_systems(Update, cycle_trail_mode)
+        .add_systems(
+            Update,
+            (
+                toggle_debug_layers,
+                (
+                    draw_voxel_grid,
+                    draw_radii,
+                    draw_steering,
+                    draw_neighbor_links,
+                    update_debug_labels,
+                ),
+            )
+                .chain(),
+        )
+        .add_systems(Update, (cycle_flow_field, apply_flow_grid, draw_flow_field))
+        .add_systems(Update, (select_tool, draw_brush))
+        .add_systems(Update, (pick_boid, draw_selection, update_inspector))
+        .add_systems(
+            Update,
+            (
+                toggle_panel,
+                drag_sliders,
+                press_panel_buttons,
+                update_panel,
+            )
+                .chain()
+                .before(respawn_boids),
+        )
+        .add_systems(
+            Update,
+            (select_nearest_boid, camera_controls, camera_modes)
+                .chain()
+                .before(update_pointer),
+        )
+        .run();
+}
+
+fn setup(mut commands: Commands, asset_server: Res<AssetServer>, params: Res<Params>) {
+    commands.spawn(Camera2d);
+    commands.insert_resource(VoxelHashMap {
+        map: Default::default(),
+        cell_size: voxel_cell_size(&params),
+    });
+    commands.insert_resource(FlowField {
+        enabled: false,
+        strength: FLOW_STRENGTH,
+        time_scale: FLOW_TIME_SCALE,
+        ..default()
+    });
+    commands.insert_resource(FlowGridSource(asset_server.load(FLOW_GRID_PATH)));
+
+    commands.spawn((
+        Inspector,
+        Text::default(),
+        TextFont {
+            font_size: 14.,
+            ..default()
+        },
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.),
+            left: Val::Px(10.),
+            padding: UiRect::all(Val::Px(8.)),
+            ..default()
+        },
+        BackgroundColor(Color::srgba(0., 0., 0., 0.6)),
+        Visibility::Hidden,
+    ));
+}
+
+fn spawn_boids(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<ColorMaterial>>,
+    mut voxels: ResMut<VoxelHashMap>,
+    mut rng: ResMut<SimRng>,
+    mut next_id: ResMut<NextBoidId>,
+    params: Res<Params>,
+    replay: Option<Res<Replay>>,
+) {
+    let assets = BoidAssets {
+        trails: meshes.add(TrailMesh::empty_mesh()),
+    };
+
+    // The whole flock is one shared mesh, drawn once per boid with its transform and colour
+    commands.spawn((
+        Mesh2d(
+            meshes.add(
+                CircularSector::new(BOID_RADIUS, f32::to_radians(BOID_SECTION_DEG))
+                    .mesh()
+                    .resolution(BOID_SECTION_RESOLUTION),
+            ),
+        ),
+        FlockInstances::default(),
+        // Instances are drawn wherever the boids are, whatever the mesh's own bounds
+        NoFrustumCulling,
+    ));
+    commands.spawn((
+        Mesh2d(assets.trails.clone()),
+        MeshMaterial2d(materials.add(ColorMaterial {
+            alpha_mode: AlphaMode2d::Blend,
+            ..default()
+        })),
+        // Behind the boids
+        Transform::from_xyz(0., 0., -1.),
+        NoFrustumCulling,
+    ));
+
+    // Replayed boids are spawned as they appear in the recording
+    if replay.is_none() {
+        spawn_flock(&mut commands, &mut voxels, &mut rng, &mut next_id, &params);
+        info!("Number of voxels: {}", voxels.map.len());
+    }
+
+    commands.insert_resource(assets);
+}
+
+/// Spawn `params.boid_number` boids at random, the first `params.leader_fraction` of them leaders.
+fn spawn_flock(
+    commands: &mut Commands,
+    voxels: &mut VoxelHashMap,
+    rng: &mut SimRng,
+    next_id: &mut NextBoidId,
+    params: &Params,
+) {
+    let n_leaders = (params.boid_number as f32 * params.leader_fraction).round() as usize;
+    let leader_heading = Vec3::new(
+        f32::to_radians(LEADER_HEADING_DEG).cos(),
+        f32::to_radians(LEADER_HEADING_DEG).sin(),
+        0.,
+    );
+
+    for i in 0..params.boid_number {
+        let x = rng.0.gen::<f32>() * 800. - 400.;
+        let y = rng.0.gen::<f32>() * 600. - 300.;
+        let translation = Vec3::new(x, y, 0.);
+        // let vx = rand::random::<f32>() * BOID_MAX_SPEED - BOID_MAX_SPEED / 2.;
+        // let vy = rand::random::<f32>() * BOID_MAX_SPEED - BOID_MAX_SPEED / 2.;
+        // let v = translation.cross(Vec3::Z).normalize() * BOID_MAX_SPEED / 6.;
+        let heading = rng.0.gen::<f32>() * std::f32::consts::TAU;
+        let v = Vec2::from_angle(heading).extend(0.) * params.min_speed;
+
+        let leader = (i < n_leaders).then_some(Leader {
+            heading: leader_heading,
+        });
+        let entity = spawn_boid(
+            commands,
+            voxels,
+            params,
+            next_id.next(),
+            translation,
+            v,
+            leader,
+        );
+        if i < n_leaders && params.player_leaders.includes(i) {
+            commands.entity(entity).insert(PlayerControlled);
+        }
+    }
+    info!(
+        "Spawned {} boids, {n_leaders} of them leaders",
+        params.boid_number
+    );
+}
+
+fn respawn_boids(
+    mut commands: Commands,
+    mut events: EventReader<Respawn>,
+    mut voxels: ResMut<VoxelHashMap>,
+    mut rng: ResMut<SimRng>,
+    mut next_id: ResMut<NextBoidId>,
+    mut leadership: ResMut<Leadership>,
+    time: Res<Time>,
+    params: Res<Params>,
+    q_boids: Query<Entity, With<Boid>>,
+) {
+    if events.read().count() == 0 {
+        return;
+    }
+
+    for entity in q_boids.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    voxels.map.clear();
+    voxels.cell_size = voxel_cell_size(&params);
+    *leadership = Leadership {
+        heading_changed_at: time.elapsed_secs(),
+        following: false,
+    };
+
+    spawn_flock(&mut commands, &mut voxels, &mut rng, &mut next_id, &params);
+}
+
+/// Whether the simulation moved this frame, for systems that measure or record it.
+fn not_paused(time: Res<Time>) -> bool {
+    time.delta_secs() > 0.
+}
+
+fn advance_tick(time: Res<Time>, mut tick: ResMut<SimTick>) {
+    if time.delta_secs() > 0. {
+        tick.0 += 1;
+    }
+}
+
+/// Write a row for each sampled boid every `Recorder::every` ticks.
+fn record_trajectories(
+    mut commands: Commands,
+    recorder: Option<ResMut<Recorder>>,
+    tick: Res<SimTick>,
+    q_boids: Query<(&BoidId, &Transform, &Velocity, &Steering), With<Boid>>,
+) {
+    let Some(mut recorder) = recorder else {
+        return;
+    };
+    if !tick.0.is_multiple_of(recorder.every) {
+        return;
+    }
+
+    for (&BoidId(id), transform, velocity, steering) in q_boids.iter() {
+        if !is_sampled(id, recorder.sample) {
+            continue;
+        }
+        let row = TrajectoryRow {
+            tick: tick.0,
+            id,
+            x: transform.translation.x,
+            y: transform.translation.y,
+            vx: velocity.0.x,
+            vy: velocity.0.y,
+            n_neighbors: steering.n_neighbors as u32,
+        };
+        if let Err(err) = recorder.writer.write(&row) {
+            error!("Stopped recording trajectories: {err}");
+            commands.remove_resource::<Recorder>();
+            return;
+        }
+    }
+}
+
+fn exit_after_ticks(cli: Res<Cli>, tick: Res<SimTick>, mut exit: EventWriter<AppExit>) {
+    if cli.ticks.is_some_and(|ticks| tick.0 >= ticks) {
+        exit.send(AppExit::Success);
+    }
+}
+
+/// Seed, starting parameters and recording options, stored in Arrow and Parquet files.
+fn run_metadata(cli: &Cli) -> std::collections::HashMap<String, String> {
+    let params = serde_json::to_string(&Params::default()).unwrap_or_default();
+    let mut metadata = std::collections::HashMap::from([
+        ("seed".to_string(), SEED.to_string()),
+        ("params".to_string(), params),
+        ("record_every".to_string(), cli.record_every.to_string()),
+        ("record_sample".to_string(), cli.record_sample.to_string()),
+    ]);
+    // Windowed runs step by the frame time
+    if cli.headless {
+        metadata.insert("timestep".to_string(), HEADLESS_TIMESTEP.to_string());
+    }
+    metadata
+}
+
+fn flush_recorder(
+    mut exit: EventReader<AppExit>,
+    recorder: Option<ResMut<Recorder>>,
+    metrics: Option<ResMut<MetricsLog>>,
+    cli: Res<Cli>,
+    correlations: Res<CorrelationAnalysis>,
+) {
+    if exit.read().count() == 0 {
+        return;
+    }
+    if let Some(mut recorder) = recorder {
+        if let Err(err) = recorder.writer.finish() {
+            error!("Could not flush trajectories: {err}");
+        }
+    }
+    if let Some(mut metrics) = metrics {
+        if let Err(err) = metrics.0.finish() {
+            error!("Could not flush metrics: {err}");
+        }
+    }
+    if let (Some(path), Some(correlations)) = (&cli.correlations, &correlations.run) {
+        match write_correlations(path, correlations) {
+            Ok(()) => info!(
+                "Wrote correlations over {} frames to {}",
+                correlations.frames,
+                path.display()
+            ),
+            Err(err) => error!("Could not write correlations: {err}"),
+        }
+    }
+}
+
+fn write_correlations(path: &std::path::Path, correlations: &Correlations) -> std::io::Result<()> {
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    correlations.write_csv(&mut file)?;
+    std::io::Write::flush(&mut file)
+}
+
+/// Move the boids to the recorded frame at the playback position, advancing it while unpaused.
+/// Boids are spawned when their id first appears and despawned once it's gone.
+fn play_replay(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut replay: ResMut<Replay>,
+    mut voxels: ResMut<VoxelHashMap>,
+    mut tick: ResMut<SimTick>,
+    params: Res<Params>,
+    mut q_boids: Query<(&mut Transform, &mut Velocity, &mut Steering), With<Boid>>,
+) {
+    let replay = &mut *replay;
+    let last_tick = replay.recording.last_tick() as f64;
+    replay.tick =
+        (replay.tick + time.delta_secs_f64() * REPLAY_TICK_RATE * replay.speed).min(last_tick);
+
+    let Some((frame_tick, rows)) = replay.recording.frame_at(replay.tick) else {
+        return;
+    };
+    if replay.shown == Some(frame_tick) {
+        return;
+    }
+    replay.shown = Some(frame_tick);
+    tick.0 = frame_tick;
+
+    let mut present = HashSet::new();
+    for row in rows {
+        present.insert(row.id);
+        let position = Vec2::new(row.x, row.y);
+        let velocity = Vec3::new(row.vx, row.vy, 0.);
+        let rotation = Quat::from_rotation_z(row.vy.atan2(row.vx) + std::f32::consts::FRAC_PI_2);
+
+        let existing = replay.entities.get(&row.id).copied();
+        match existing.and_then(|entity| Some((entity, q_boids.get_mut(entity).ok()?))) {
+            Some((entity, (mut transform, mut boid_velocity, mut steering))) => {
+                let old_position = transform.translation.xy();
+                transform.translation = position.extend(transform.translation.z);
+                transform.rotation = rotation;
+                voxels.update_entity(old_position, position, entity);
+                boid_velocity.0 = velocity;
+                steering.n_neighbors = row.n_neighbors as usize;
+            }
+            // Spawned this frame and not yet in the world
+            None if existing.is_some() => {}
+            None => {
+                let entity = spawn_boid(
+                    &mut commands,
+                    &mut voxels,
+                    &params,
+                    BoidId(row.id),
+                    position.extend(0.),
+                    velocity,
+                    None,
+                );
+                commands.entity(entity).insert((
+                    Transform::from_translation(position.extend(0.)).with_rotation(rotation),
+                    Steering {
+                        n_neighbors: row.n_neighbors as usize,
+                        ..default()
+                    },
+                ));
+                replay.entities.insert(row.id, entity);
+            }
+        }
+    }
+
+    replay.entities.retain(|id, entity| {
+        if present.contains(id) {
+            return true;
+        }
+        if let Ok((transform, ..)) = q_boids.get(*entity) {
+            voxels.remove(transform.translation.xy(), *entity);
+        }
+        commands.entity(*entity).despawn_recursive();
+        false
+    });
+}
+
+/// `[`/`]` halve or double the playback speed, `←`/`→` jump back or forward, `,`/`.` step one
+/// recorded frame and `Home` restarts.
+fn replay_controls(keys: Res<ButtonInput<KeyCode>>, mut replay: ResMut<Replay>) {
+    if keys.just_pressed(KeyCode::BracketLeft) {
+        replay.speed = (replay.speed / 2.).max(REPLAY_MIN_SPEED);
+    }
+    if keys.just_pressed(KeyCode::BracketRight) {
+        replay.speed = (replay.speed * 2.).min(REPLAY_MAX_SPEED);
+    }
+
+    let first_tick = replay.recording.first_tick() as f64;
+    let last_tick = replay.recording.last_tick() as f64;
+    let mut tick = replay.tick;
+    if keys.just_pressed(KeyCode::ArrowLeft) {
+        tick -= REPLAY_SCRUB_TICKS;
+    }
+    if keys.just_pressed(KeyCode::ArrowRight) {
+        tick += REPLAY_SCRUB_TICKS;
+    }
+    let index = replay.recording.frame_index(tick);
+    if keys.just_pressed(KeyCode::Comma) {
+        if let Some((frame_tick, _)) = replay.recording.frame(index.saturating_sub(1)) {
+            tick = frame_tick as f64;
+        }
+    }
+    if keys.just_pressed(KeyCode::Period) {
+        if let Some((frame_tick, _)) = replay.recording.frame(index + 1) {
+            tick = frame_tick as f64;
+        }
+    }
+    if keys.just_pressed(KeyCode::Home) {
+        tick = first_tick;
+    }
+    if tick != replay.tick {
+        replay.tick = tick.clamp(first_tick, last_tick);
+    }
+}
+
+fn spawn_replay_bar(mut commands: Commands) {
+    commands
+        .spawn((
+            Node {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(10.),
+                left: Val::Px(230.),
+                right: Val::Px(10.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(4.),
+                padding: UiRect::all(Val::Px(8.)),
+                ..default()
+            },
+            BackgroundColor(Color::srgba(0., 0., 0., 0.6)),
+            Interaction::default(),
+            FocusPolicy::Block,
+        ))
+        .with_children(|timeline| {
+            timeline.spawn((
+                ReplayLabel,
+                Text::default(),
+                TextFont {
+                    font_size: PANEL_FONT_SIZE,
+                    ..default()
+                },
+            ));
+            timeline
+                .spawn((
+                    ReplayBar,
+                    Node {
+                        width: Val::Percent(100.),
+                        height: Val::Px(10.),
+                        ..default()
+                    },
+                    BackgroundColor(Color::srgb(0.25, 0.25, 0.25)),
+                    Interaction::default(),
+                    RelativeCursorPosition::default(),
+                ))
+                .with_child((
+                    ReplayBarFill,
+                    Node {
+                        height: Val::Percent(100.),
+                        ..default()
+                    },
+                    BackgroundColor(Color::srgb(0.4, 0.7, 1.)),
+                    FocusPolicy::Pass,
+                ));
+        });
+}
+
+fn scrub_replay_bar(
+    mut replay: ResMut<Replay>,
+    q_bar: Query<(&Interaction, &RelativeCursorPosition), With<ReplayBar>>,
+) {
+    for (interaction, cursor) in q_bar.iter() {
+        if *interaction != Interaction::Pressed {
+            continue;
+        }
+        let Some(cursor) = cursor.normalized else {
+            continue;
+        };
+        let first_tick = replay.recording.first_tick() as f64;
+        let last_tick = replay.recording.last_tick() as f64;
+        replay.tick = first_tick + cursor.x.clamp(0., 1.) as f64 * (last_tick - first_tick);
+    }
+}
+
+fn update_replay_bar(
+    replay: Res<Replay>,
+    time: Res<Time<Virtual>>,
+    mut q_fill: Query<&mut Node, With<ReplayBarFill>>,
+    mut q_label: Query<&mut Text, With<ReplayLabel>>,
+) {
+    let first_tick = replay.recording.first_tick();
+    let last_tick = replay.recording.last_tick();
+    let shown = replay.shown.unwrap_or(first_tick);
+    let progress = if last_tick > first_tick {
+        (shown - first_tick) as f32 / (last_tick - first_tick) as f32
+    } else {
+        1.
+    };
+    for mut node in q_fill.iter_mut() {
+        node.width = Val::Percent(progress * 100.);
+    }
+    for mut text in q_label.iter_mut() {
+        let state = if time.is_paused() {
+            "paused"
+        } else if shown >= last_tick {
+            "ended"
+        } else {
+            "playing"
+        };
+        text.0 = format!("Tick {shown} / {last_tick}  x{}  {state}", replay.speed);
+    }
+}
+
+/// `F5` writes the parameters, RNG state, voxel cell size and every boid to `SNAPSHOT_PATH`.
+fn save_snapshot(
+    keys: Res<ButtonInput<KeyCode>>,
+    params: Res<Params>,
+    rng: Res<SimRng>,
+    voxels: Res<VoxelHashMap>,
+    q_boids: Query<
+        (
+            &BoidId,
+            &Transform,
+            &Velocity,
+            &Wander,
+            Option<&Leader>,
+            Has<PlayerControlled>,
+        ),
+        With<Boid>,
+    >,
+) {
+    if !keys.just_pressed(KeyCode::F5) {
+        return;
+    }
+
+    let snapshot = Snapshot {
+        params: params.clone(),
+        rng: rng.0.clone(),
+        cell_size: voxels.cell_size,
+        boids: q_boids
+            .iter()
+            .map(
+                |(id, transform, velocity, wander, leader, player_controlled)| BoidState {
+                    id: Some(id.0),
+                    position: transform.translation.xy().to_array(),
+                    velocity: velocity.0.xy().to_array(),
+                    wander: wander.0,
+                    leader_heading: leader.map(|leader| leader.heading.xy().to_array()),
+                    player_controlled,
+                },
+            )
+            .collect(),
+    };
+    match snapshot.save(SNAPSHOT_PATH) {
+        Ok(()) => info!("Saved {} boids to {SNAPSHOT_PATH}", snapshot.boids.len()),
+        Err(err) => error!("{err}"),
+    }
+}
+
+/// `F9` replaces the flock, parameters and RNG state with the snapshot at `SNAPSHOT_PATH`,
+/// rebuilding the voxel hash map.
+fn load_snapshot(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut params: ResMut<Params>,
+    mut rng: ResMut<SimRng>,
+    mut voxels: ResMut<VoxelHashMap>,
+    mut next_id: ResMut<NextBoidId>,
+    mut leadership: ResMut<Leadership>,
+    time: Res<Time>,
+    q_boids: Query<Entity, With<Boid>>,
+) {
+    if !keys.just_pressed(KeyCode::F9) {
+        return;
+    }
+    let snapshot = match Snapshot::<Params>::load(SNAPSHOT_PATH) {
+        Ok(snapshot) => snapshot,
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+
+    for entity in q_boids.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    voxels.map.clear();
+    voxels.cell_size = snapshot.cell_size;
+    *params = snapshot.params;
+    rng.0 = snapshot.rng;
+    *leadership = Leadership {
+        heading_changed_at: time.elapsed_secs(),
+        following: false,
+    };
+
+    // Snapshots from before boids had ids get new ones after the saved ids
+    next_id.0 = snapshot
+        .boids
+        .iter()
+        .filter_map(|boid| boid.id)
+        .max()
+        .map_or(0, |id| id + 1);
+    for boid in snapshot.boids.iter() {
+        let id = boid.id.map_or_else(|| next_id.next(), BoidId);
+        let leader = boid.leader_heading.map(|heading| Leader {
+            heading: Vec2::from(heading).extend(0.),
+        });
+        let entity = spawn_boid(
+            &mut commands,
+            &mut voxels,
+            &params,
+            id,
+            Vec2::from(boid.position).extend(0.),
+            Vec2::from(boid.velocity).extend(0.),
+            leader,
+        );
+        let mut entity = commands.entity(entity);
+        entity.insert(Wander(boid.wander));
+        if boid.player_controlled {
+            entity.insert(PlayerControlled);
+        }
+    }
+    info!("Loaded {} boids from {SNAPSHOT_PATH}", snapshot.boids.len());
+}
+
+fn voxel_cell_size(params: &Params) -> f32 {
+    params.alignment_radius * params.cell_size_factor
+}
+
+/// Push parameter changes to the boids, rebuilding the voxel hash map when the alignment
+/// radius changes its cell size.
+fn apply_params(
+    params: Res<Params>,
+    mut voxels: ResMut<VoxelHashMap>,
+    mut q_boids: Query<(Entity, &mut Boid, &Transform)>,
+) {
+    if !params.is_changed() {
+        return;
+    }
+
+    for (_, mut boid, _) in q_boids.iter_mut() {
+        boid.avoid_radius = params.separation_radius;
+        boid.align_radius = params.alignment_radius;
+    }
+
+    let cell_size = voxel_cell_size(&params);
+    if voxels.cell_size != cell_size {
+        voxels.map.clear();
+        voxels.cell_size = cell_size;
+        for (entity, _, transform) in q_boids.iter() {
+            voxels.insert(transform.translation.xy(), entity);
+        }
+    }
+}
+
+/// Hand the arrow keys to a different set of leaders when `Params::player_leaders` changes.
+/// Leaders are taken in spawn order, so "one" is the first leader still alive.
+fn apply_player_leaders(
+    mut commands: Commands,
+    params: Res<Params>,
+    mut last: Local<Option<PlayerLeaders>>,
+    q_leaders: Query<Entity, With<Leader>>,
+) {
+    let previous = last.replace(params.player_leaders);
+    // The flock is spawned with the right leaders
+    if previous.is_none() || previous == Some(params.player_leaders) {
+        return;
+    }
+
+    let mut leaders = q_leaders.iter().collect::<Vec<_>>();
+    leaders.sort();
+    for (i, entity) in leaders.into_iter().enumerate() {
+        if params.player_leaders.includes(i) {
+            commands.entity(entity).insert(PlayerControlled);
+        } else {
+            commands.entity(entity).remove::<PlayerControlled>();
+        }
+    }
+}
+
+/// Spawn a boid and add it to the voxel hash map.
+fn spawn_boid(
+    commands: &mut Commands,
+    voxels: &mut VoxelHashMap,
+    params: &Params,
+    id: BoidId,
+    translation: Vec3,
+    velocity: Vec3,
+    leader: Option<Leader>,
+) -> Entity {
+    let color = if leader.is_some() {
+        LEADER_COLOR
+    } else {
+        Color::WHITE
+    };
+
+    let mut entity = commands.spawn((
+        Boid {
+            avoid_radius: params.separation_radius,
+            align_radius: params.alignment_radius,
+            separation_accumulator: Vec3::ZERO,
+            alignment_accumulator: Vec3::ZERO,
+            alignment_weight: 0.,
+            position_accumulator: Vec3::ZERO,
+            n_neighbors: 0,
+        },
+        id,
+        BoidColor(color),
+        Acceleration {
+            value: Vec3::ZERO,
+            last_velocity: velocity,
+        },
+        Transform::from_translation(translation),
+        Velocity(velocity),
+        Steering::default(),
+        Wander::default(),
+        ClusterId::default(),
+    ));
+    if let Some(leader) = leader {
+        entity.insert(leader);
+    }
+
+    // Add to voxel hash map
+    let entity = entity.id();
+    voxels.insert(translation.xy(), entity);
+    entity
+}
+
+#[allow(dead_code)]
+fn boids_behavior_fast(
+    mut q_boids: Query<(&mut Boid, &Transform, &Velocity)>,
+    voxels: Res<VoxelHashMap>,
+) {
+    let mut velocity: HashMap<(i64, i64), Vec2> = HashMap::new();
+    let mut position: HashMap<(i64, i64), Vec2> = HashMap::new();
+    // let mut n_neighbors: HashMap<(i64, i64), usize> = HashMap::new();
+
+    for (pos, entities) in voxels.map.iter() {
+        let (x, y) = pos;
+        let mut n = 0;
+        for entity in entities {
+            if let Ok((_boid, transform, vel)) = q_boids.get(*entity) {
+                n += 1;
+                *velocity.entry((*x, *y)).or_insert(vel.0.xy()) += vel.0.xy();
+                *position
+                    .entry((*x, *y))
+                    .or_insert(transform.translation.xy()) += transform.translation.xy();
+            }
+        }
+        *velocity.entry((*x, *y)).or_insert(Vec2::ZERO) /= n as f32;
+        *position.entry((*x, *y)).or_insert(Vec2::ZERO) /= n as f32;
+        // n_neighbors.insert((*x, *y), n);
+    }
+
+    for (mut boid, transform, _) in q_boids.iter_mut() {
+        let mut separation_acc = Vec2::ZERO;
+        let mut alignment_acc = Vec2::ZERO;
+        let mut position_acc = Vec2::ZERO;
+
+        let mut n_neighbors = 0;
+        let neighbors =
+            voxels.get_neighbor_keys_within(transform.translation.xy(), BOID_ALIGNMENT_RADIUS);
+
+        for key in neighbors {
+            let Some(other_position) = position.get(&key) else {
+                continue;
+            };
+            let Some(other_velocity) = velocity.get(&key) else {
+                continue;
+            };
+
+            let distance = transform.translation.xy().distance_squared(*other_position);
+            if distance < boid.avoid_radius * boid.avoid_radius {
+                separation_acc += transform.translation.xy() - other_position;
+            } else if distance < boid.align_radius * boid.align_radius {
+                alignment_acc += other_velocity;
+                position_acc += other_position;
+                n_neighbors += 1;
+            }
+        }
+
+        boid.separation_accumulator = separation_acc.extend(0.);
+        boid.alignment_accumulator = alignment_acc.extend(0.);
+        boid.position_accumulator = position_acc.extend(0.);
+        boid.n_neighbors = n_neighbors;
+    }
+}
+
+fn boids_behavior(
+    mut q_boids: Query<(Entity, &mut Boid, &Transform)>,
+    q_boids_other: Query<(&Transform, &Velocity, Has<Leader>), With<Boid>>,
+    voxels: Res<VoxelHashMap>,
+    params: Res<Params>,
+) {
+    for (entity, mut boid, transform) in q_boids.iter_mut() {
+        let mut separation = Vec3::ZERO;
+        let mut alignment = Vec3::ZERO;
+        let mut alignment_weight = 0.;
+        let mut position = Vec3::ZERO;
+
+        let mut n_neighbors = 0;
+
+        // Every cell within the alignment radius, so any cell size finds all neighbours
+        let neighbors = voxels
+            .get_neighbor_keys_within(transform.translation.xy(), boid.align_radius)
+            .into_iter()
+            .filter_map(|key| voxels.map.get(&key))
+            .flatten()
+            .copied()
+            .filter(|other| *other != entity)
+            .collect::<Vec<_>>();
+
+        // for (other_transform, other_velocity) in q_boids_other.iter() {
+        for other_entity in neighbors {
+            let (other_transform, other_velocity, other_is_leader) =
+                q_boids_other.get(other_entity).unwrap();
+            // if boid == other_boid {
+            //     continue;
+            // }
+            // Separation
+            let distance = transform
+                .translation
+                .distance_squared(other_transform.translation);
+            if distance < boid.avoid_radius * boid.avoid_radius {
+                separation += transform.translation - other_transform.translation;
+            }
+            // Alignment
+            else if distance < boid.align_radius * boid.align_radius {
+                let weight = if other_is_leader {
+                    params.leader_alignment_weight
+                } else {
+                    1.
+                };
+                alignment += other_velocity.0 * weight;
+                alignment_weight += weight;
+                position += other_transform.translation;
+                n_neighbors += 1;
+            }
+        }
+
+        boid.separation_accumulator = separation;
+        boid.alignment_accumulator = alignment;
+        boid.alignment_weight = alignment_weight;
+        boid.position_accumulator = position;
+        boid.n_neighbors = n_neighbors;
+
+        // // Separation
+        // velocity.0 += separation * BOID_SEPARATION_FACTOR;
+
+        // if n_neighbors > 0 {
+        //     alignment /= n_neighbors as f32;
+        //     average /= n_neighbors as f32;
+
+        //     // Alignment
+        //     let vel = velocity.0;
+        //     velocity.0 += (alignment - vel) * BOID_ALIGNMENT_FACTOR;
+
+        //     // Cohesion
+        //     velocity.0 += (average - transform.translation) * BOID_COHESION_FACTOR;
+        // }
+    }
+}
+
+fn wander(
+    time: Res<Time>,
+    mut rng: ResMut<SimRng>,
+    mut query: Query<(&mut Velocity, &mut Wander), With<Boid>>,
+    params: Res<Params>,
+) {
+    let dt = time.delta_secs();
+    if params.wander_mode == WanderMode::Off || dt == 0. {
+        return;
+    }
+
+    for (mut velocity, mut wander) in query.iter_mut() {
+        let normal: f32 = rng.0.sample(StandardNormal);
+        let v = velocity.0.xy();
+
+        match params.wander_mode {
+            WanderMode::Circle => {
+                wander.0 += params.wander_noise * dt.sqrt() * normal;
+                let direction =
+                    wander_circle_direction(v, wander.0, WANDER_DISTANCE, WANDER_RADIUS);
+                velocity.0 += ((direction * v.length() - v) * WANDER_FACTOR * dt).extend(0.);
+            }
+            WanderMode::OrnsteinUhlenbeck => {
+                wander.0 = ornstein_uhlenbeck_step(
+                    wander.0,
+                    WANDER_TIMESCALE,
+                    params.wander_noise,
+                    dt,
+                    normal,
+                );
+                velocity.0 = Quat::from_rotation_z(wander.0 * dt) * velocity.0;
+            }
+            WanderMode::Off => {}
+        }
+    }
+}
+
+/// `Tab` selects the boid nearest the centre of the view.
+fn select_nearest_boid(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    camera_q: Query<&Transform, With<Camera2d>>,
+    q_boids: Query<(Entity, &Transform), With<Boid>>,
+    q_selected: Query<Entity, With<Selected>>,
+) {
+    if !keys.just_pressed(KeyCode::Tab) {
+        return;
+    }
+    let Ok(camera) = camera_q.get_single() else {
+        return;
+    };
+
+    let center = camera.translation.xy();
+    let nearest = q_boids.iter().min_by(|(_, a), (_, b)| {
+        let da = a.translation.xy().distance_squared(center);
+        let db = b.translation.xy().distance_squared(center);
+        da.total_cmp(&db)
+    });
+    if let Some((entity, _)) = nearest {
+        select_boid(&mut commands, &q_selected, entity);
+    }
+}
+
+/// With the select tool, left click selects the nearest boid under the cursor and right click
+/// clears the selection.
+fn pick_boid(
+    mut commands: Commands,
+    mouse: Res<ButtonInput<MouseButton>>,
+    pointer: Res<Pointer>,
+    tool: Res<PointerTool>,
+    voxels: Res<VoxelHashMap>,
+    q_boids: Query<&Transform, With<Boid>>,
+    q_selected: Query<Entity, With<Selected>>,
+) {
+    if *tool != PointerTool::Select || pointer.over_ui {
+        return;
+    }
+    if mouse.just_pressed(MouseButton::Right) {
+        for selected in q_selected.iter() {
+            commands.entity(selected).remove::<Selected>();
+        }
+        return;
+    }
+    let Some(position) = pointer.position else {
+        return;
+    };
+    if !mouse.just_pressed(MouseButton::Left) {
+        return;
+    }
+
+    let nearest = voxels
+        .get_neighbor_keys_within(position, SELECT_RADIUS)
+        .iter()
+        .filter_map(|key| voxels.map.get(key))
+        .flatten()
+        .filter_map(|entity| {
+            let distance = q_boids
+                .get(*entity)
+                .ok()?
+                .translation
+                .xy()
+                .distance(position);
+            (distance < SELECT_RADIUS).then_some((*entity, distance))
+        })
+        .min_by(|(_, a), (_, b)| a.total_cmp(b));
+    if let Some((entity, _)) = nearest {
+        select_boid(&mut commands, &q_selected, entity);
+    }
+}
+
+/// Outline the selected boid, its candidate neighbours from the voxel hash map, and link the
+/// neighbours within its alignment radius.
+fn draw_selection(
+    mut gizmos: Gizmos,
+    voxels: Res<VoxelHashMap>,
+    q_selected: Query<(&Boid, &Transform), With<Selected>>,
+    q_boids: Query<&Transform, With<Boid>>,
+) {
+    let Ok((boid, transform)) = q_selected.get_single() else {
+        return;
+    };
+    let position = transform.translation.xy();
+
+    gizmos.circle_2d(position, BOID_RADIUS * 1.5, Color::srgb(1., 1., 0.));
+    for entity in voxels.get_neighbor_entities(position) {
+        let Ok(other) = q_boids.get(entity) else {
+            continue;
+        };
+        let other = other.translation.xy();
+        if other.distance_squared(position) < boid.align_radius * boid.align_radius {
+            gizmos.line_2d(position, other, Color::srgba(1., 1., 0., 0.6));
+            gizmos.circle_2d(other, BOID_RADIUS, Color::srgb(0., 1., 1.));
+        } else {
+            gizmos.circle_2d(other, BOID_RADIUS, Color::srgba(0., 1., 1., 0.25));
+        }
+    }
+}
+
+fn toggle_debug_layers(keys: Res<ButtonInput<KeyCode>>, mut layers: ResMut<DebugLayers>) {
+    if keys.just_pressed(KeyCode::KeyG) {
+        layers.grid = !layers.grid;
+    }
+    let layers = &mut *layers;
+    for (key, name, scope) in [
+        (KeyCode::KeyR, "Radii", &mut layers.radii),
+        (KeyCode::KeyX, "Steering arrows", &mut layers.vectors),
+        (KeyCode::KeyN, "Neighbour links", &mut layers.links),
+        (KeyCode::KeyI, "IDs", &mut layers.ids),
+    ] {
+        if keys.just_pressed(key) {
+            *scope = scope.next();
+            info!("{name}: {:?}", *scope);
+        }
+    }
+}
+
+fn draw_radii(
+    mut gizmos: Gizmos,
+    layers: Res<DebugLayers>,
+    query: Query<(&Boid, &Transform, Has<Selected>)>,
+) {
+    if layers.radii == DebugScope::Off {
+        return;
+    }
+
+    for (boid, transform, selected) in query.iter() {
+        if !layers.radii.includes(selected) {
+            continue;
+        }
+        let position = transform.translation.xy();
+        gizmos.circle_2d(position, boid.avoid_radius, Color::srgba(1., 0., 0., 0.4));
+        gizmos.circle_2d(position, boid.align_radius, Color::srgba(0., 1., 0., 0.4));
+    }
+}
+
+/// Arrows from each boid for the separation (red), alignment (green), cohesion (blue) and
+/// boundary (magenta) steering of the last step, and its velocity (white).
+fn draw_steering(
+    mut gizmos: Gizmos,
+    layers: Res<DebugLayers>,
+    query: Query<(&Transform, &Velocity, &Steering, Has<Selected>)>,
+) {
+    if layers.vectors == DebugScope::Off {
+        return;
+    }
+
+    for (transform, velocity, steering, selected) in query.iter() {
+        if !layers.vectors.includes(selected) {
+            continue;
+        }
+        let start = transform.translation.xy();
+        for (vector, scale, color) in [
+            (
+                steering.separation,
+                STEERING_ARROW_SCALE,
+                Color::srgb(1., 0.2, 0.2),
+            ),
+            (
+                steering.alignment,
+                STEERING_ARROW_SCALE,
+                Color::srgb(0.2, 1., 0.2),
+            ),
+            (
+                steering.cohesion,
+                STEERING_ARROW_SCALE,
+                Color::srgb(0.3, 0.5, 1.),
+            ),
+            (
+                steering.boundary,
+                STEERING_ARROW_SCALE,
+                Color::srgb(1., 0.2, 1.),
+            ),
+            (velocity.0, VELOCITY_ARROW_SCALE, Color::WHITE),
+        ] {
+            if vector.length_squared() > 0. {
+                gizmos.arrow_2d(start, start + vector.xy() * scale, color);
+            }
+        }
+    }
+}
+
+/// Outline every occupied voxel cell, coloured by how many boids it holds relative to the
+/// fullest cell, and the cells searched for the selected boid's neighbours. The boid's own
+/// cell is not part of that search.
+fn draw_voxel_grid(
+    mut gizmos: Gizmos,
+    layers: Res<DebugLayers>,
+    voxels: Res<VoxelHashMap>,
+    q_selected: Query<&Transform, With<Selected>>,
+) {
+    if !layers.grid {
+        return;
+    }
+
+    let size = Vec2::splat(voxels.cell_size);
+    let cell_center = |key| voxels.key_to_vec2(key) + size / 2.;
+    let max = voxels
+        .map
+        .values()
+        .map(|cell| cell.len())
+        .max()
+        .unwrap_or(1);
+    for (key, entities) in voxels.map.iter() {
+        let t = entities.len() as f32 / max as f32;
+        // Skip the darkest end of the palette, which is invisible on the background
+        let color = Palette::MAGMA.sample(0.3 + 0.7 * t);
+        gizmos.rect_2d(cell_center(*key), size, color);
+    }
+
+    for transform in q_selected.iter() {
+        let position = transform.translation.xy();
+        for key in voxels.get_neighbor_keys(position) {
+            gizmos.rect_2d(cell_center(key), size * 0.9, Color::srgb(0., 1., 1.));
+        }
+        gizmos.rect_2d(
+            cell_center(voxels.vec2_to_key(position)),
+            size * 0.9,
+            Color::srgba(1., 1., 0., 0.5),
+        );
+    }
+}
+
+fn draw_neighbor_links(
+    mut gizmos: Gizmos,
+    layers: Res<DebugLayers>,
+    voxels: Res<VoxelHashMap>,
+    query: Query<(&Boid, &Transform, Has<Selected>)>,
+    q_boids: Query<&Transform, With<Boid>>,
+) {
+    if layers.links == DebugScope::Off {
+        return;
+    }
+
+    for (boid, transform, selected) in query.iter() {
+        if !layers.links.includes(selected) {
+            continue;
+        }
+        let position = transform.translation.xy();
+        for entity in voxels.get_neighbor_entities(position) {
+            let Ok(other) = q_boids.get(entity) else {
+                continue;
+            };
+            let other = other.translation.xy();
+            if other.distance_squared(position) < boid.align_radius * boid.align_radius {
+                gizmos.line_2d(position, other, Color::srgba(1., 1., 1., 0.3));
+            }
+        }
+    }
+}
+
+/// Label boids in view with their entity IDs, reusing a pool of text entities.
+fn update_debug_labels(
+    mut commands: Commands,
+    layers: Res<DebugLayers>,
+    q_camera: Query<(&Transform, &OrthographicProjection), (With<Camera2d>, Without<DebugLabel>)>,
+    q_boids: Query<(Entity, &Transform, Has<Selected>), (With<Boid>, Without<DebugLabel>)>,
+    mut q_labels: Query<(&mut Text2d, &mut Transform, &mut Visibility), With<DebugLabel>>,
+) {
+    let Ok((camera, projection)) = q_camera.get_single() else {
+        return;
+    };
+    let view = Rect::from_center_size(
+        camera.translation.xy() + projection.area.center(),
+        projection.area.size(),
+    );
+
+    let labelled = q_boids
+        .iter()
+        .filter(|(_, transform, selected)| {
+            layers.ids.includes(*selected) && view.contains(transform.translation.xy())
+        })
+        .take(DEBUG_ID_LIMIT)
+        .collect::<Vec<_>>();
+
+    let mut labels = q_labels.iter_mut();
+    for (entity, transform, _) in labelled.iter() {
+        let position = transform.translation.xy() + Vec2::new(0., BOID_RADIUS * 1.5);
+        match labels.next() {
+            Some((mut text, mut label_transform, mut visibility)) => {
+                text.0 = entity.to_string();
+                label_transform.translation = position.extend(1.);
+                *visibility = Visibility::Inherited;
+            }
+            None => {
+                commands.spawn((
+                    DebugLabel,
+                    Text2d::new(entity.to_string()),
+                    TextFont {
+                        font_size: 10.,
+                        ..default()
+                    },
+                    Transform::from_translation(position.extend(1.)),
+                ));
+            }
+        }
+    }
+    for (_, _, mut visibility) in labels {
+        *visibility = Visibility::Hidden;
+    }
+}
+
+fn update_inspector(
+    mut q_inspector: Query<(&mut Text, &mut Visibility), With<Inspector>>,
+    q_selected: Query<
+        (
+            Entity,
+            &Transform,
+            &Velocity,
+            &Steering,
+            &ClusterId,
+            Has<Leader>,
+        ),
+        With<Selected>,
+    >,
+    voxels: Res<VoxelHashMap>,
+    clusters: Res<Clusters>,
+) {
+    let Ok((mut text, mut visibility)) = q_inspector.get_single_mut() else {
+        return;
+    };
+    let Ok((entity, transform, velocity, steering, cluster, is_leader)) = q_selected.get_single()
+    else {
+        *visibility = Visibility::Hidden;
+        return;
+    };
+    *visibility = Visibility::Inherited;
+
+    let p = transform.translation;
+    let v = velocity.0;
+    // A lone boid is its own cluster
+    let flock = clusters
+        .clustering
+        .clusters
+        .get(cluster.index)
+        .copied()
+        .unwrap_or(Cluster {
+            size: 1,
+            centroid: p.xy(),
+            velocity: v.xy(),
+        });
+    text.0 = format!(
+        "Boid {entity}{}\n\
+         position    ({:8.1}, {:8.1})\n\
+         velocity    ({:8.1}, {:8.1})\n\
+         speed       {:8.1}\n\
+         neighbours  {:8} in radius, {} in nearby cells\n\
+         cluster     {:8} of {} boids\n\
+           centre    ({:8.1}, {:8.1})\n\
+           velocity  ({:8.1}, {:8.1})\n\
+         separation  ({:8.2}, {:8.2})\n\
+         alignment   ({:8.2}, {:8.2})\n\
+         cohesion    ({:8.2}, {:8.2})\n\
+         boundary    ({:8.2}, {:8.2})",
+        if is_leader { " (leader)" } else { "" },
+        p.x,
+        p.y,
+        v.x,
+        v.y,
+        v.length(),
+        steering.n_neighbors,
+        voxels.get_neighbor_entities(p.xy()).len(),
+        cluster.flock,
+        flock.size,
+        flock.centroid.x,
+        flock.centroid.y,
+        flock.velocity.x,
+        flock.velocity.y,
+        steering.separation.x,
+        steering.separation.y,
+        steering.alignment.x,
+        steering.alignment.y,
+        steering.cohesion.x,
+        steering.cohesion.y,
+        steering.boundary.x,
+        steering.boundary.y,
+    );
+}
+
+fn select_boid(
+    commands: &mut Commands,
+    q_selected: &Query<Entity, With<Selected>>,
+    entity: Entity,
+) {
+    for selected in q_selected.iter() {
+        commands.entity(selected).remove::<Selected>();
+    }
+    commands.entity(entity).insert(Selected);
+}
+
+/// Middle mouse drag and WASD pan, the scroll wheel zooms about the cursor.
+/// `C` follows the selected boid and `H` frames the whole flock; panning returns to free mode.
+fn camera_controls(
+    time: Res<Time>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    motion: Res<AccumulatedMouseMotion>,
+    scroll: Res<AccumulatedMouseScroll>,
+    mut mode: ResMut<CameraMode>,
+    mut camera_q: Query<(
+        &Camera,
+        &GlobalTransform,
+        &mut Transform,
+        &mut OrthographicProjection,
+    )>,
+    window_q: Query<&Window, With<PrimaryWindow>>,
+) {
+    let Ok((camera, camera_global, mut transform, mut projection)) = camera_q.get_single_mut()
+    else {
+        return;
+    };
+
+    if keys.just_pressed(KeyCode::KeyC) {
+        *mode = if *mode == CameraMode::Follow {
+            CameraMode::Free
+        } else {
+            CameraMode::Follow
+        };
+        info!("Camera: {:?}", *mode);
+    }
+    if keys.just_pressed(KeyCode::KeyH) {
+        *mode = if *mode == CameraMode::Frame {
+            CameraMode::Free
+        } else {
+            CameraMode::Frame
+        };
+        info!("Camera: {:?}", *mode);
+    }
+
+    // Pan
+    let mut pan = Vec2::ZERO;
+    if mouse.pressed(MouseButton::Middle) {
+        pan += Vec2::new(-motion.delta.x, motion.delta.y) * projection.scale;
+    }
+    let mut direction = Vec2::ZERO;
+    if keys.pressed(KeyCode::KeyW) {
+        direction.y += 1.;
+    }
+    if keys.pressed(KeyCode::KeyS) {
+        direction.y -= 1.;
+    }
+    if keys.pressed(KeyCode::KeyA) {
+        direction.x -= 1.;
+    }
+    if keys.pressed(KeyCode::KeyD) {
+        direction.x += 1.;
+    }
+    pan += direction * CAMERA_PAN_SPEED * projection.scale * time.delta_secs();
+    if pan != Vec2::ZERO {
+        *mode = CameraMode::Free;
+        transform.translation += pan.extend(0.);
+    }
+
+    // Zoom about the cursor
+    let lines = match scroll.unit {
+        MouseScrollUnit::Line => scroll.delta.y,
+        MouseScrollUnit::Pixel => scroll.delta.y / 16.,
+    };
+    if lines != 0. {
+        let old_scale = projection.scale;
+        projection.scale =
+            (old_scale * CAMERA_ZOOM_STEP.powf(-lines)).clamp(CAMERA_MIN_SCALE, CAMERA_MAX_SCALE);
+
+        let cursor = window_q
+            .get_single()
+            .ok()
+            .and_then(|window| window.cursor_position())
+            .and_then(|cursor| camera.viewport_to_world_2d(camera_global, cursor).ok());
+        if let (Some(cursor), CameraMode::Free) = (cursor, *mode) {
+            let offset = transform.translation.xy() - cursor;
+            transform.translation =
+                (cursor + offset * projection.scale / old_scale).extend(transform.translation.z);
+        }
+    }
+}
+
+fn camera_modes(
+    time: Res<Time>,
+    mut mode: ResMut<CameraMode>,
+    mut camera_q: Query<(&mut Transform, &mut OrthographicProjection), With<Camera2d>>,
+    q_selected: Query<&Transform, (With<Selected>, Without<Camera2d>)>,
+    q_boids: Query<&Transform, (With<Boid>, Without<Camera2d>)>,
+    window_q: Query<&Window, With<PrimaryWindow>>,
+) {
+    let Ok((mut transform, mut projection)) = camera_q.get_single_mut() else {
+        return;
+    };
+    let t = (CAMERA_SMOOTHING * time.delta_secs()).min(1.);
+
+    match *mode {
+        CameraMode::Free => {}
+        CameraMode::Follow => {
+            let Ok(selected) = q_selected.get_single() else {
+                // Nothing selected, or the selected boid was erased
+                *mode = CameraMode::Free;
+                return;
+            };
+            let target = selected.translation.xy();
+            transform.translation = transform
+                .translation
+                .xy()
+                .lerp(target, t)
+                .extend(transform.translation.z);
+        }
+        CameraMode::Frame => {
+            let Some(bounds) = q_boids
+                .iter()
+                .map(|boid| Rect::from_center_size(boid.translation.xy(), Vec2::ZERO))
+                .reduce(|a, b| a.union(b))
+            else {
+                return;
+            };
+            let view = window_q
+                .get_single()
+                .map(|window| window.size())
+                .unwrap_or(Vec2::splat(800.));
+            let size = (bounds.size() + 2. * BOID_RADIUS) * CAMERA_FRAME_MARGIN / view;
+            let scale = size.max_element().clamp(CAMERA_MIN_SCALE, CAMERA_MAX_SCALE);
+
+            transform.translation = transform
+                .translation
+                .xy()
+                .lerp(bounds.center(), t)
+                .extend(transform.translation.z);
+            projection.scale += (scale - projection.scale) * t;
+        }
+    }
+}
+
+fn update_pointer(
+    mouse: Res<ButtonInput<MouseButton>>,
+    touches: Res<Touches>,
+    mut pointer: ResMut<Pointer>,
+    window_q: Query<&Window, With<PrimaryWindow>>,
+    camera_q: Query<(&Camera, &GlobalTransform)>,
+    q_interactions: Query<&Interaction>,
+) {
+    pointer.previous = pointer.position;
+    pointer.over_ui = q_interactions
+        .iter()
+        .any(|interaction| *interaction != Interaction::None);
+
+    if pointer
+        .touch
+        .is_none_or(|id| touches.get_pressed(id).is_none())
+    {
+        // The earliest finger down this frame, or any still down if that one was lifted
+        pointer.touch = touches
+            .iter_just_pressed()
+            .map(|touch| touch.id())
+            .min()
+            .or_else(|| touches.iter().map(|touch| touch.id()).min());
+    }
+
+    let n_touches = touches.iter().count();
+    let screen_position = if n_touches > 0 {
+        pointer.primary = n_touches == 1;
+        pointer.secondary = n_touches > 1;
+        pointer
+            .touch
+            .and_then(|id| touches.get_pressed(id))
+            .map(|touch| touch.position())
+    } else {
+        pointer.primary = mouse.pressed(MouseButton::Left);
+        pointer.secondary = mouse.pressed(MouseButton::Right);
+        window_q
+            .get_single()
+            .ok()
+            .and_then(|window| window.cursor_position())
+    };
+
+    pointer.position = camera_q.get_single().ok().zip(screen_position).and_then(
+        |((camera, camera_transform), position)| {
+            camera.viewport_to_world_2d(camera_transform, position).ok()
+        },
+    );
+    if pointer.over_ui {
+        pointer.primary = false;
+        pointer.secondary = false;
+    }
+}
+
+fn select_tool(keys: Res<ButtonInput<KeyCode>>, mut tool: ResMut<PointerTool>) {
+    let selected = if keys.just_pressed(KeyCode::Digit1) {
+        PointerTool::Attract
+    } else if keys.just_pressed(KeyCode::Digit2) {
+        PointerTool::Brush
+    } else if keys.just_pressed(KeyCode::Digit3) {
+        PointerTool::Select
+    } else {
+        return;
+    };
+    if *tool != selected {
+        info!("Tool: {:?}", selected);
+        *tool = selected;
+    }
+}
+
+fn pointer_attractor(
+    pointer: Res<Pointer>,
+    tool: Res<PointerTool>,
+    mut attractor: ResMut<Attractor>,
+) {
+    attractor.position = None;
+    if *tool != PointerTool::Attract {
+        return;
+    }
+
+    if pointer.secondary {
+        attractor.strength = -ATTRACTOR_STRENGTH;
+    } else if pointer.primary {
+        attractor.strength = ATTRACTOR_STRENGTH;
+    } else {
+        return;
+    }
+    attractor.position = pointer.position;
+}
+
+/// Paint boids under the brush up to `BRUSH_DENSITY`, or erase them, keeping the voxel hash map
+/// in sync so it never holds despawned entities.
+fn brush(
+    mut commands: Commands,
+    time: Res<Time>,
+    pointer: Res<Pointer>,
+    tool: Res<PointerTool>,
+    mut carry: ResMut<BrushCarry>,
+    mut rng: ResMut<SimRng>,
+    mut voxels: ResMut<VoxelHashMap>,
+    mut next_id: ResMut<NextBoidId>,
+    params: Res<Params>,
+    q_boids: Query<&Transform, With<Boid>>,
+) {
+    let Some(center) = pointer.position else {
+        return;
+    };
+    if *tool != PointerTool::Brush || !(pointer.primary || pointer.secondary) {
+        carry.0 = 0.;
+        return;
+    }
+
+    let under_brush = voxels
+        .get_neighbor_keys_within(center, BRUSH_RADIUS)
+        .iter()
+        .filter_map(|key| voxels.map.get(key))
+        .flatten()
+        .filter_map(|entity| {
+            let position = q_boids.get(*entity).ok()?.translation.xy();
+            (position.distance_squared(center) < BRUSH_RADIUS * BRUSH_RADIUS)
+                .then_some((*entity, position))
+        })
+        .collect::<Vec<_>>();
+
+    if pointer.secondary {
+        for (entity, position) in under_brush {
+            voxels.remove(position, entity);
+            commands.entity(entity).despawn_recursive();
+        }
+        return;
+    }
+
+    let area = std::f32::consts::PI * BRUSH_RADIUS * BRUSH_RADIUS;
+    let target = (BRUSH_DENSITY * area / (100. * 100.)) as usize;
+    carry.0 += BRUSH_RATE * time.delta_secs();
+    let n = (carry.0 as usize).min(target.saturating_sub(under_brush.len()));
+    carry.0 -= carry.0.floor();
+
+    let stroke = pointer
+        .previous
+        .map(|previous| center - previous)
+        .filter(|stroke| stroke.length_squared() > 0.);
+    for _ in 0..n {
+        // Uniform in the disk
+        let r = BRUSH_RADIUS * rng.0.gen::<f32>().sqrt();
+        let offset = Vec2::from_angle(rng.0.gen::<f32>() * std::f32::consts::TAU) * r;
+        let random_heading = Vec2::from_angle(rng.0.gen::<f32>() * std::f32::consts::TAU);
+        let heading = match params.brush_heading {
+            BrushHeading::Random => random_heading,
+            BrushHeading::Outward => offset.try_normalize().unwrap_or(random_heading),
+            BrushHeading::Stroke => stroke.map_or(random_heading, Vec2::normalize),
+        };
+
+        spawn_boid(
+            &mut commands,
+            &mut voxels,
+            &params,
+            next_id.next(),
+            (center + offset).extend(0.),
+            heading.extend(0.) * params.min_speed,
+            None,
+        );
+    }
+}
+
+fn draw_brush(mut gizmos: Gizmos, pointer: Res<Pointer>, tool: Res<PointerTool>) {
+    if *tool != PointerTool::Brush {
+        return;
+    }
+    if let Some(center) = pointer.position {
+        gizmos.circle_2d(center, BRUSH_RADIUS, Color::srgba(1., 1., 1., 0.3));
+    }
+}
+
+fn attract_boids(
+    time: Res<Time>,
+    attractor: Res<Attractor>,
+    voxels: Res<VoxelHashMap>,
+    mut query: Query<(&mut Velocity, &Transform), With<Boid>>,
+) {
+    let Some(position) = attractor.position else {
+        return;
+    };
+
+    for key in voxels.get_neighbor_keys_within(position, ATTRACTOR_RADIUS) {
+        let Some(entities) = voxels.map.get(&key) else {
+            continue;
+        };
+        for entity in entities {
+            let Ok((mut velocity, transform)) = query.get_mut(*entity) else {
+                continue;
+            };
+            let offset = position - transform.translation.xy();
+            let distance = offset.length();
+            if distance > ATTRACTOR_RADIUS || distance == 0. {
+                continue;
+            }
+            // Linear falloff towards the edge of the radius
+            let falloff = 1. - distance / ATTRACTOR_RADIUS;
+            velocity.0 +=
+                (offset / distance * attractor.strength * falloff * time.delta_secs()).extend(0.);
+        }
+    }
+}
+
+fn move_boids(
+    time: Res<Time>,
+    mut query: Query<(
+        Entity,
+        &mut Boid,
+        &mut Transform,
+        &mut Velocity,
+        &mut Steering,
+        Option<&Leader>,
+    )>,
+    mut voxels: ResMut<VoxelHashMap>,
+    flow: Res<FlowField>,
+    params: Res<Params>,
+) {
+    for (entity, mut boid, mut transform, mut velocity, mut steering, leader) in query.iter_mut() {
+        // Separation
+        steering.separation = boid.separation_accumulator * params.separation_factor;
+        velocity.0 += steering.separation;
+
+        let n_neighbors = boid.n_neighbors;
+        steering.n_neighbors = n_neighbors;
+        steering.alignment = Vec3::ZERO;
+        steering.cohesion = Vec3::ZERO;
+        if n_neighbors > 0 {
+            let alignment_weight = boid.alignment_weight;
+            boid.alignment_accumulator /= alignment_weight;
+            boid.position_accumulator /= n_neighbors as f32;
+
+            // Alignment
+            steering.alignment =
+                (boid.alignment_accumulator - velocity.0) * params.alignment_factor;
+            velocity.0 += steering.alignment;
+
+            // Cohesion
+            steering.cohesion =
+                (boid.position_accumulator - transform.translation) * params.cohesion_factor;
+            velocity.0 += steering.cohesion;
+        }
+
+        // Goal
+        if let Some(leader) = leader {
+            let vel = velocity.0;
+            velocity.0 += (leader.heading * params.max_speed - vel) * params.leader_goal_factor;
+        }
+
+        // Reset values
+        boid.separation_accumulator = Vec3::ZERO;
+        boid.alignment_accumulator = Vec3::ZERO;
+        boid.alignment_weight = 0.;
+        boid.position_accumulator = Vec3::ZERO;
+        boid.n_neighbors = 0;
+
+        // Cap the velocity
+        if velocity.0.length() > params.max_speed {
+            velocity.0 = velocity.0.normalize() * params.max_speed;
+        }
+
+        if velocity.0.length() < params.min_speed {
+            velocity.0 = velocity.0.normalize() * params.min_speed;
+        }
+
+        let old_translation = transform.translation.xy();
+        let drift = flow.sample(old_translation).extend(0.);
+        transform.translation += (velocity.0 + drift) * time.delta_secs();
+        voxels.update_entity(old_translation, transform.translation.xy(), entity);
+
+        // Rotate to face the direction of the velocity vector
+        let angle = ops::atan2(velocity.0.y, velocity.0.x);
+        transform.rotation = Quat::from_rotation_z(angle + std::f32::consts::FRAC_PI_2);
+    }
+}
+
+fn steer_player_leader(
+    time: Res<Time>,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut query: Query<&mut Leader, With<PlayerControlled>>,
+    mut leadership: ResMut<Leadership>,
+) {
+    let mut turn = 0.;
+    if keys.pressed(KeyCode::ArrowLeft) {
+        turn += 1.;
+    }
+    if keys.pressed(KeyCode::ArrowRight) {
+        turn -= 1.;
+    }
+    if turn == 0. {
+        return;
+    }
+
+    let rotation =
+        Quat::from_rotation_z(turn * f32::to_radians(LEADER_TURN_RATE_DEG) * time.delta_secs());
+    for mut leader in query.iter_mut() {
+        leader.heading = rotation * leader.heading;
+    }
+    leadership.heading_changed_at = time.elapsed_secs();
+    leadership.following = false;
+}
+
+fn advance_flow_field(time: Res<Time>, mut flow: ResMut<FlowField>) {
+    flow.advance(time.delta_secs());
+}
+
+/// `F` cycles through the flow patterns (off, wind, vortex, shear, curl noise, loaded grid),
+/// `Shift+F` toggles the arrow overlay.
+fn cycle_flow_field(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut flow: ResMut<FlowField>,
+    mut overlay: ResMut<FlowOverlay>,
+    source: Res<FlowGridSource>,
+    grids: Res<Assets<FlowGrid>>,
+    window_q: Query<&Window, With<PrimaryWindow>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyF) {
+        return;
+    }
+    if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        overlay.0 = !overlay.0;
+        return;
+    }
+
+    let next = match (flow.enabled, &flow.pattern) {
+        (false, _) => Some(FlowPattern::Uniform(Vec2::X)),
+        (true, FlowPattern::Uniform(_)) => Some(FlowPattern::Vortex {
+            center: Vec2::ZERO,
+            radius: 200.,
+        }),
+        (true, FlowPattern::Vortex { .. }) => Some(FlowPattern::Shear { width: 300. }),
+        (true, FlowPattern::Shear { .. }) => Some(FlowPattern::CurlNoise {
+            scale: FLOW_NOISE_SCALE,
+        }),
+        (true, FlowPattern::CurlNoise { .. }) => grids
+            .get(&source.0)
+            .map(|grid| FlowPattern::Grid(map_to_window(grid, window_q.get_single().ok()))),
+        (true, _) => None,
+    };
+
+    match next {
+        Some(FlowPattern::Grid(grid)) => {
+            info!("Flow field: grid {}x{}", grid.width, grid.height);
+            flow.pattern = FlowPattern::Grid(grid);
+            flow.enabled = true;
+        }
+        Some(pattern) => {
+            info!("Flow field: {:?}", pattern);
+            flow.pattern = pattern;
+            flow.enabled = true;
+        }
+        None => {
+            info!("Flow field: off");
+            flow.enabled = false;
+        }
+    }
+}
+
+/// Switch to the flow grid whenever its file changes, so edits show up immediately.
+fn apply_flow_grid(
+    mut events: EventReader<AssetEvent<FlowGrid>>,
+    mut flow: ResMut<FlowField>,
+    source: Res<FlowGridSource>,
+    grids: Res<Assets<FlowGrid>>,
+    window_q: Query<&Window, With<PrimaryWindow>>,
+) {
+    for event in events.read() {
+        if !event.is_modified(&source.0) {
+            continue;
+        }
+        if let Some(grid) = grids.get(&source.0) {
+            info!("Reloaded flow grid {}", FLOW_GRID_PATH);
+            flow.pattern = FlowPattern::Grid(map_to_window(grid, window_q.get_single().ok()));
+            flow.enabled = true;
+        }
+    }
+}
+
+fn map_to_window(grid: &FlowGrid, window: Option<&Window>) -> FlowGrid {
+    let half = world_bounds(window);
+    FlowGrid {
+        bounds: Rect::from_center_half_size(Vec2::ZERO, half),
+        ..grid.clone()
+    }
+}
+
+fn draw_flow_field(
+    mut gizmos: Gizmos,
+    flow: Res<FlowField>,
+    overlay: Res<FlowOverlay>,
+    window_q: Query<&Window, With<PrimaryWindow>>,
+) {
+    if !overlay.0 || !flow.enabled {
+        return;
+    }
+
+    let Vec2 {
+        x: width,
+        y: height,
+    } = world_bounds(window_q.get_single().ok());
+
+    let nx = (width / FLOW_ARROW_SPACING) as i32;
+    let ny = (height / FLOW_ARROW_SPACING) as i32;
+    for i in -nx..=nx {
+        for j in -ny..=ny {
+            let start = Vec2::new(i as f32, j as f32) * FLOW_ARROW_SPACING;
+            let v = flow.sample(start);
+            let color = Color::srgba(
+                0.4,
+                0.7,
+                1.,
+                0.2 + 0.8 * (v.length() / FLOW_STRENGTH).min(1.),
+            );
+            gizmos.arrow_2d(start, start + v * FLOW_ARROW_SCALE, color);
+        }
+    }
+}
+
+fn measure_leadership(
+    time: Res<Time>,
+    mut diagnostics: Diagnostics,
+    mut leadership: ResMut<Leadership>,
+    q_leaders: Query<&Leader>,
+    q_followers: Query<&Velocity, (With<Boid>, Without<Leader>)>,
+) {
+    let heading = q_leaders
+        .iter()
+        .map(|leader| leader.heading)
+        .sum::<Vec3>()
+        .normalize_or_zero();
+    if heading == Vec3::ZERO {
+        return;
+    }
+
+    let (sum, n) = q_followers.iter().fold((0., 0), |(sum, n), velocity| {
+        (sum + velocity.0.normalize_or_zero().dot(heading), n + 1)
+    });
+    if n == 0 {
+        return;
+    }
+    let accuracy = sum / n as f32;
+    diagnostics.add_measurement(&LEADERSHIP_ACCURACY, || accuracy as f64);
+
+    if !leadership.following && accuracy >= LEADERSHIP_THRESHOLD {
+        leadership.following = true;
+        info!(
+            "Flock following leaders after {:.2}s (accuracy {:.3})",
+            time.elapsed_secs() - leadership.heading_changed_at,
+            accuracy
+        );
+    }
+}
+
+/// Order parameters of the whole flock, for the diagnostics log.
+fn measure_flock(
+    mut diagnostics: Diagnostics,
+    mut latest: ResMut<LatestMetrics>,
+    voxels: Res<VoxelHashMap>,
+    q_boids: Query<(Entity, &Transform, &Velocity), With<Boid>>,
+) {
+    let boids = q_boids
+        .iter()
+        .map(|(entity, transform, velocity)| (entity, transform.translation.xy(), velocity.0.xy()))
+        .collect::<Vec<_>>();
+    let distance = mean_nearest_neighbor_distance(&boids, &voxels);
+    let metrics = FlockMetrics::new(&boids);
+
+    let row = &mut latest.0;
+    row.nearest_neighbor_distance = distance.unwrap_or(f32::NAN);
+    row.polarization = metrics.map_or(f32::NAN, |metrics| metrics.polarization);
+    row.milling = metrics.map_or(f32::NAN, |metrics| metrics.milling);
+    row.group_radius = metrics.map_or(f32::NAN, |metrics| metrics.group_radius);
+    row.mean_speed = metrics.map_or(f32::NAN, |metrics| metrics.mean_speed);
+
+    let Some(metrics) = metrics else {
+        return;
+    };
+    diagnostics.add_measurement(&POLARIZATION, || metrics.polarization as f64);
+    diagnostics.add_measurement(&MILLING, || metrics.milling as f64);
+    diagnostics.add_measurement(&GROUP_RADIUS, || metrics.group_radius as f64);
+    diagnostics.add_measurement(&MEAN_SPEED, || metrics.mean_speed as f64);
+    if let Some(distance) = distance {
+        diagnostics.add_measurement(&NEAREST_NEIGHBOR_DISTANCE, || distance as f64);
+    }
+}
+
+/// Label connected groups of boids, linked when within the alignment radius, and follow them as
+/// flocks from the last frame.
+fn find_clusters(
+    mut diagnostics: Diagnostics,
+    mut clusters: ResMut<Clusters>,
+    mut tracker: ResMut<ClusterTracker>,
+    mut splits: EventWriter<FlockSplit>,
+    mut merges: EventWriter<FlockMerged>,
+    mut latest: ResMut<LatestMetrics>,
+    voxels: Res<VoxelHashMap>,
+    params: Res<Params>,
+    mut q_boids: Query<(Entity, &Transform, &Velocity, &mut ClusterId), With<Boid>>,
+) {
+    let boids = q_boids
+        .iter()
+        .map(|(entity, transform, velocity, _)| {
+            (entity, transform.translation.xy(), velocity.0.xy())
+        })
+        .collect::<Vec<_>>();
+    let clustering = Clustering::new(&boids, &voxels, params.alignment_radius);
+    let entities = boids.iter().map(|(entity, ..)| *entity).collect::<Vec<_>>();
+    let changes = tracker.update(&entities, &clustering);
+
+    // Iterates in the same order as above
+    for ((_, _, _, mut cluster), label) in q_boids.iter_mut().zip(&clustering.labels) {
+        *cluster = ClusterId {
+            index: *label,
+            flock: changes.ids[*label],
+        };
+    }
+    latest.0.clusters = clustering.clusters.len() as u32;
+    latest.0.largest_cluster = clustering.clusters.first().map_or(0, |c| c.size as u32);
+    if let Some(largest) = clustering.clusters.first() {
+        diagnostics.add_measurement(&CLUSTER_COUNT, || clustering.clusters.len() as f64);
+        diagnostics.add_measurement(&LARGEST_CLUSTER, || largest.size as f64);
+    }
+    splits.send_batch(changes.splits);
+    merges.send_batch(changes.merges);
+    *clusters = Clusters {
+        clustering,
+        flocks: changes.ids,
+    };
+}
+
+fn log_flock_events(
+    mut splits: EventReader<FlockSplit>,
+    mut merges: EventReader<FlockMerged>,
+    clusters: Res<Clusters>,
+    tick: Res<SimTick>,
+) {
+    let size = |id: &FlockId| {
+        clusters
+            .flocks
+            .iter()
+            .position(|flock| flock == id)
+            .map_or(0, |index| clusters.clustering.clusters[index].size)
+    };
+    for split in splits.read() {
+        let children = split
+            .children
+            .iter()
+            .map(|child| format!("{child} ({})", size(child)))
+            .collect::<Vec<_>>();
+        info!(
+            "Tick {}: flock {} split into {}",
+            tick.0,
+            split.parent,
+            children.join(", ")
+        );
+    }
+    for merge in merges.read() {
+        let parents = merge
+            .parents
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        info!(
+            "Tick {}: flocks {} merged into {} ({})",
+            tick.0,
+            parents.join(", "),
+            merge.child,
+            size(&merge.child)
+        );
+    }
+}
+
+/// Bin the pairs of boids every `CORRELATION_EVERY` ticks, while writing `--correlations` or
+/// showing the plots. Densities are relative to the area inside the boundary.
+fn measure_correlations(
+    mut diagnostics: Diagnostics,
+    mut analysis: ResMut<CorrelationAnalysis>,
+    tick: Res<SimTick>,
+    voxels: Res<VoxelHashMap>,
+    window_q: Query<&Window, With<PrimaryWindow>>,
+    q_boids: Query<(Entity, &Transform, &Velocity), With<Boid>>,
+) {
+    if !tick.0.is_multiple_of(CORRELATION_EVERY) {
+        return;
+    }
+    if analysis.run.is_none() && analysis.plot.is_none() {
+        return;
+    }
+    let area = 4. * world_bounds(window_q.get_single().ok()).element_product();
+    let boids = q_boids
+        .iter()
+        .map(|(entity, transform, velocity)| (entity, transform.translation.xy(), velocity.0.xy()))
+        .collect::<Vec<_>>();
+
+    let mut frame = Correlations::new(CORRELATION_MAX_RADIUS, CORRELATION_BINS);
+    frame.add_frame(&boids, &voxels, area);
+    if let Some(length) = frame.correlation_length() {
+        diagnostics.add_measurement(&CORRELATION_LENGTH, || length as f64);
+    }
+    let analysis = &mut *analysis;
+    for correlations in [&mut analysis.run, &mut analysis.plot]
+        .into_iter()
+        .flatten()
+    {
+        correlations.add(&frame);
+    }
+}
+
+/// Complete the tick's metrics, add them to the plots and write them with `--metrics`.
+fn record_metrics(
+    mut commands: Commands,
+    mut latest: ResMut<LatestMetrics>,
+    mut plot: ResMut<MetricsPlot>,
+    log: Option<ResMut<MetricsLog>>,
+    tick: Res<SimTick>,
+    time: Res<Time>,
+    store: Res<DiagnosticsStore>,
+) {
+    let row = &mut latest.0;
+    row.tick = tick.0;
+    row.time = time.elapsed_secs_f64();
+    row.fps = store
+        .get(&FrameTimeDiagnosticsPlugin::FPS)
+        .and_then(|fps| fps.smoothed())
+        .map_or(f32::NAN, |fps| fps as f32);
+
+    plot.polarization.push(row.polarization);
+    plot.clusters.push(row.clusters as f32);
+    plot.fps.push(row.fps);
+    if let Some(mut log) = log {
+        if let Err(err) = log.0.write(row) {
+            error!("Stopped writing metrics: {err}");
+            commands.remove_resource::<MetricsLog>();
+        }
+    }
+}
+
+fn spawn_panel(mut commands: Commands) {
+    let font = TextFont {
+        font_size: PANEL_FONT_SIZE,
+        ..default()
+    };
 
-fn main() {
-    App::new()
-        .add_plugins(DefaultPlugins.set(AssetPlugin {
-            // Wasm builds will check for meta files (that don't exist) if this isn't set.
-            // This causes errors and even panics in web builds on itch.
-            // See https://github.com/bevyengine/bevy_github_ci_template/issues/48.
-            meta_check: AssetMetaCheck::Never,
-            ..default()
-        }))
-        .add_plugins((FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin::default()))
-        .add_systems(Startup, (setup, spawn_boids).chain())
-        // .add_systems(Update, (avoid_boundary, move_boids).chain())
-        .add_systems(
-            Update,
-            (boids_behavior, avoid_boundary, move_boids).chain(), // color_boids
-        )
-        .run();
+    commands
+        .spawn((
+            Panel,
+            Node {
+                position_type: PositionType::Absolute,
+                top: Val::Px(10.),
+                right: Val::Px(10.),
+                width: Val::Px(PANEL_WIDTH),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(4.),
+                padding: UiRect::all(Val::Px(8.)),
+                ..default()
+            },
+            BackgroundColor(Color::srgba(0., 0., 0., 0.6)),
+            // Keep clicks on the panel from reaching the world
+            Interaction::default(),
+            FocusPolicy::Block,
+        ))
+        .with_children(|panel| {
+            for (i, spec) in SLIDERS.iter().enumerate() {
+                panel
+                    .spawn(Node {
+                        align_items: AlignItems::Center,
+                        column_gap: Val::Px(6.),
+                        ..default()
+                    })
+                    .with_children(|row| {
+                        row.spawn((
+                            Text::new(spec.label),
+                            font.clone(),
+                            Node {
+                                width: Val::Px(120.),
+                                ..default()
+                            },
+                        ));
+                        row.spawn((
+                            Slider(i),
+                            Node {
+                                width: Val::Px(120.),
+                                height: Val::Px(10.),
+                                ..default()
+                            },
+                            BackgroundColor(Color::srgb(0.25, 0.25, 0.25)),
+                            Interaction::default(),
+                            RelativeCursorPosition::default(),
+                        ))
+                        .with_child((
+                            SliderFill(i),
+                            Node {
+                                height: Val::Percent(100.),
+                                ..default()
+                            },
+                            BackgroundColor(Color::srgb(0.4, 0.7, 1.)),
+                            FocusPolicy::Pass,
+                        ));
+                        row.spawn((SliderValue(i), Text::default(), font.clone()));
+                    });
+            }
+
+            panel
+                .spawn(Node {
+                    flex_wrap: FlexWrap::Wrap,
+                    column_gap: Val::Px(4.),
+                    row_gap: Val::Px(4.),
+                    margin: UiRect::top(Val::Px(4.)),
+                    ..default()
+                })
+                .with_children(|buttons| {
+                    for button in [
+                        PanelButton::Boundary,
+                        PanelButton::Wander,
+                        PanelButton::PlayerLeaders,
+                        PanelButton::BrushHeading,
+                        PanelButton::Colors,
+                        PanelButton::Palette,
+                        PanelButton::Trails,
+                        PanelButton::FlowArrows,
+                        PanelButton::Grid,
+                        PanelButton::Radii,
+                        PanelButton::SteeringArrows,
+                        PanelButton::NeighborLinks,
+                        PanelButton::Ids,
+                        PanelButton::Pause,
+                        PanelButton::Reset,
+                        PanelButton::Respawn,
+                    ] {
+                        buttons
+                            .spawn((
+                                button,
+                                Button,
+                                Node {
+                                    padding: UiRect::axes(Val::Px(6.), Val::Px(3.)),
+                                    ..default()
+                                },
+                                BackgroundColor(Color::srgb(0.2, 0.2, 0.2)),
+                            ))
+                            .with_child((Text::default(), font.clone()));
+                    }
+                });
+        });
 }
 
-fn setup(mut commands: Commands) {
-    commands.spawn(Camera2d);
-    commands.insert_resource(VoxelHashMap {
-        map: Default::default(),
-        cell_size: 2. * BOID_ALIGNMENT_RADIUS / 3., // 3 cells should equal alignment diameter
-                                                    // cell_size: BOID_SEPARATION_RADIUS / 2.,
-    });
+/// `P` shows or hides the parameter panel, `Space` pauses the simulation.
+fn toggle_panel(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut q_panel: Query<&mut Visibility, With<Panel>>,
+    mut time: ResMut<Time<Virtual>>,
+) {
+    if keys.just_pressed(KeyCode::KeyP) {
+        for mut visibility in q_panel.iter_mut() {
+            visibility.toggle_visible_hidden();
+        }
+    }
+    if keys.just_pressed(KeyCode::Space) {
+        toggle_pause(&mut time);
+    }
+}
 
-    // Render voxel cell boundaries
-    if DEBUG {
-        // commands.spawn();
+fn toggle_pause(time: &mut Time<Virtual>) {
+    if time.is_paused() {
+        time.unpause();
+    } else {
+        time.pause();
     }
 }
 
-fn spawn_boids(
-    mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
-    mut voxels: ResMut<VoxelHashMap>,
+fn drag_sliders(
+    mut params: ResMut<Params>,
+    q_sliders: Query<(&Slider, &Interaction, &RelativeCursorPosition)>,
 ) {
-    let shape = meshes.add(CircularSector::new(
-        BOID_RADIUS,
-        f32::to_radians(BOID_SECTION_DEG),
-    ));
-    let inner = meshes.add(Annulus::new(
-        BOID_SEPARATION_RADIUS - 1.,
-        BOID_SEPARATION_RADIUS,
-    ));
-    let outer = meshes.add(Annulus::new(
-        BOID_ALIGNMENT_RADIUS - 1.,
-        BOID_ALIGNMENT_RADIUS,
-    ));
+    for (slider, interaction, cursor) in q_sliders.iter() {
+        if *interaction != Interaction::Pressed {
+            continue;
+        }
+        let Some(cursor) = cursor.normalized else {
+            continue;
+        };
 
-    for _ in 0..BOID_NUMBER {
-        let x = rand::random::<f32>() * 800. - 400.;
-        let y = rand::random::<f32>() * 600. - 300.;
-        let translation = Vec3::new(x, y, 0.);
-        // let vx = rand::random::<f32>() * BOID_MAX_SPEED - BOID_MAX_SPEED / 2.;
-        // let vy = rand::random::<f32>() * BOID_MAX_SPEED - BOID_MAX_SPEED / 2.;
-        // let v = translation.cross(Vec3::Z).normalize() * BOID_MAX_SPEED / 6.;
-        let v = Vec3::ZERO;
-
-        let entity = commands
-            .spawn((
-                Boid {
-                    avoid_radius: BOID_SEPARATION_RADIUS,
-                    align_radius: BOID_ALIGNMENT_RADIUS,
-                    separation_accumulator: Vec3::ZERO,
-                    alignment_accumulator: Vec3::ZERO,
-                    position_accumulator: Vec3::ZERO,
-                    n_neighbors: 0,
-                },
-                Mesh2d(shape.clone()),
-                MeshMaterial2d(materials.add(Color::WHITE)),
-                Transform::from_translation(translation),
-                Velocity(v),
-            ))
-            .with_children(|parent| {
-                parent.spawn_empty().insert_if(
-                    (
-                        Mesh2d(inner.clone()),
-                        MeshMaterial2d(materials.add(Color::linear_rgba(1., 0., 0., 0.1))),
-                        Transform::from_xyz(0., 0., 0.),
-                    ),
-                    || DEBUG,
-                );
-                parent.spawn_empty().insert_if(
-                    (
-                        Mesh2d(outer.clone()),
-                        MeshMaterial2d(materials.add(Color::linear_rgba(0., 1., 0., 0.1))),
-                        Transform::from_xyz(0., 0., 0.),
-                    ),
-                    || DEBUG,
-                );
-            })
-            .id();
+        let spec = &SLIDERS[slider.0];
+        let value = spec.min + cursor.x.clamp(0., 1.) * (spec.max - spec.min);
+        let mut new_params = params.clone();
+        (spec.set)(&mut new_params, value);
+        // Only trigger change detection when the value actually moves
+        params.set_if_neq(new_params);
+    }
+}
 
-        // Add to voxel hash map
-        voxels.insert(translation.xy(), entity);
+fn press_panel_buttons(
+    mut params: ResMut<Params>,
+    mut color_mode: ResMut<ColorMode>,
+    mut palette: ResMut<ColorPalette>,
+    mut trail_mode: ResMut<TrailMode>,
+    mut overlay: ResMut<FlowOverlay>,
+    mut layers: ResMut<DebugLayers>,
+    mut time: ResMut<Time<Virtual>>,
+    mut respawn: EventWriter<Respawn>,
+    q_buttons: Query<(&PanelButton, &Interaction), Changed<Interaction>>,
+) {
+    for (button, interaction) in q_buttons.iter() {
+        if *interaction != Interaction::Pressed {
+            continue;
+        }
+        match button {
+            PanelButton::Boundary => {
+                params.boundary = match params.boundary {
+                    BoundaryMode::Avoid => BoundaryMode::Periodic,
+                    BoundaryMode::Periodic => BoundaryMode::Avoid,
+                }
+            }
+            PanelButton::Wander => {
+                params.wander_mode = match params.wander_mode {
+                    WanderMode::Off => WanderMode::Circle,
+                    WanderMode::Circle => WanderMode::OrnsteinUhlenbeck,
+                    WanderMode::OrnsteinUhlenbeck => WanderMode::Off,
+                }
+            }
+            PanelButton::PlayerLeaders => params.player_leaders = params.player_leaders.next(),
+            PanelButton::BrushHeading => params.brush_heading = params.brush_heading.next(),
+            PanelButton::Colors => *color_mode = color_mode.next(),
+            PanelButton::Palette => palette.0 = (palette.0 + 1) % PALETTES.len(),
+            PanelButton::Trails => *trail_mode = trail_mode.next(),
+            PanelButton::FlowArrows => overlay.0 = !overlay.0,
+            PanelButton::Grid => layers.grid = !layers.grid,
+            PanelButton::Radii => layers.radii = layers.radii.next(),
+            PanelButton::SteeringArrows => layers.vectors = layers.vectors.next(),
+            PanelButton::NeighborLinks => layers.links = layers.links.next(),
+            PanelButton::Ids => layers.ids = layers.ids.next(),
+            PanelButton::Pause => toggle_pause(&mut time),
+            PanelButton::Reset => *params = Params::default(),
+            PanelButton::Respawn => {
+                respawn.send(Respawn);
+            }
+        }
     }
-    println!("Number of voxels: {}", voxels.map.len());
 }
 
-fn boids_behavior_fast(
-    mut q_boids: Query<(&mut Boid, &Transform, &Velocity)>,
-    voxels: Res<VoxelHashMap>,
+fn update_panel(
+    params: Res<Params>,
+    color_mode: Res<ColorMode>,
+    palette: Res<ColorPalette>,
+    trail_mode: Res<TrailMode>,
+    overlay: Res<FlowOverlay>,
+    layers: Res<DebugLayers>,
+    time: Res<Time<Virtual>>,
+    mut q_fills: Query<(&SliderFill, &mut Node)>,
+    mut q_values: Query<(&SliderValue, &mut Text)>,
+    q_buttons: Query<(&PanelButton, &Interaction, &Children)>,
+    mut q_button_colors: Query<(&PanelButton, &Interaction, &mut BackgroundColor)>,
+    mut q_text: Query<&mut Text, Without<SliderValue>>,
 ) {
-    let mut velocity: HashMap<(i64, i64), Vec2> = HashMap::new();
-    let mut position: HashMap<(i64, i64), Vec2> = HashMap::new();
-    // let mut n_neighbors: HashMap<(i64, i64), usize> = HashMap::new();
+    for (fill, mut node) in q_fills.iter_mut() {
+        let spec = &SLIDERS[fill.0];
+        let t = ((spec.get)(&params) - spec.min) / (spec.max - spec.min);
+        node.width = Val::Percent(100. * t.clamp(0., 1.));
+    }
+    for (value, mut text) in q_values.iter_mut() {
+        let spec = &SLIDERS[value.0];
+        text.0 = format!("{:.*}", spec.precision, (spec.get)(&params));
+    }
 
-    for (pos, entities) in voxels.map.iter() {
-        let (x, y) = pos;
-        let mut n = 0;
-        for entity in entities {
-            if let Ok((_boid, transform, vel)) = q_boids.get(*entity) {
-                n += 1;
-                *velocity.entry((*x, *y)).or_insert(vel.0.xy()) += vel.0.xy();
-                *position
-                    .entry((*x, *y))
-                    .or_insert(transform.translation.xy()) += transform.translation.xy();
+    for (button, _, children) in q_buttons.iter() {
+        let label = match button {
+            PanelButton::Boundary => format!("Boundary: {:?}", params.boundary),
+            PanelButton::Wander => format!("Wander: {:?}", params.wander_mode),
+            PanelButton::PlayerLeaders => {
+                format!("Player leaders: {:?}", params.player_leaders)
+            }
+            PanelButton::BrushHeading => format!("Brush heading: {:?}", params.brush_heading),
+            PanelButton::Colors => format!("Colours: {:?}", *color_mode),
+            PanelButton::Palette => format!("Palette: {}", PALETTES[palette.0].name),
+            PanelButton::Trails => format!("Trails: {:?}", *trail_mode),
+            PanelButton::FlowArrows => format!("Flow arrows: {}", on_off(overlay.0)),
+            PanelButton::Grid => format!("Grid: {}", on_off(layers.grid)),
+            PanelButton::Radii => format!("Radii: {:?}", layers.radii),
+            PanelButton::SteeringArrows => format!("Steering arrows: {:?}", layers.vectors),
+            PanelButton::NeighborLinks => format!("Neighbour links: {:?}", layers.links),
+            PanelButton::Ids => format!("IDs: {:?}", layers.ids),
+            PanelButton::Pause if time.is_paused() => "Resume".to_string(),
+            PanelButton::Pause => "Pause".to_string(),
+            PanelButton::Reset => "Reset parameters".to_string(),
+            PanelButton::Respawn => "Respawn".to_string(),
+        };
+        for child in children.iter() {
+            if let Ok(mut text) = q_text.get_mut(*child) {
+                text.0.clone_from(&label);
             }
         }
-        *velocity.entry((*x, *y)).or_insert(Vec2::ZERO) /= n as f32;
-        *position.entry((*x, *y)).or_insert(Vec2::ZERO) /= n as f32;
-        // n_neighbors.insert((*x, *y), n);
     }
+    for (_, interaction, mut color) in q_button_colors.iter_mut() {
+        color.0 = match interaction {
+            Interaction::Pressed => Color::srgb(0.4, 0.4, 0.4),
+            Interaction::Hovered => Color::srgb(0.3, 0.3, 0.3),
+            Interaction::None => Color::srgb(0.2, 0.2, 0.2),
+        };
+    }
+}
 
-    for (mut boid, transform, _) in q_boids.iter_mut() {
-        let mut separation_acc = Vec2::ZERO;
-        let mut alignment_acc = Vec2::ZERO;
-        let mut position_acc = Vec2::ZERO;
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
 
-        let mut n_neighbors = 0;
-        let neighbors =
-            voxels.get_neighbor_keys_within(transform.translation.xy(), BOID_ALIGNMENT_RADIUS);
+fn color_boids(
+    mut query: Query<(
+        &Velocity,
+        &Steering,
+        &mut Acceleration,
+        Has<Leader>,
+        &ClusterId,
+        &mut BoidColor,
+    )>,
+    clusters: Res<Clusters>,
+    color_mode: Res<ColorMode>,
+    palette: Res<ColorPalette>,
+    params: Res<Params>,
+    time: Res<Time>,
+) {
+    let palette = PALETTES[palette.0];
+    let dt = time.delta_secs();
 
-        for key in neighbors {
-            let Some(other_position) = position.get(&key) else {
+    for (velocity, steering, mut acceleration, leader, cluster, mut color) in query.iter_mut() {
+        // Tracked in every mode so it is ready when switched to. Kept while paused.
+        if dt > 0. {
+            acceleration.value = (velocity.0 - acceleration.last_velocity) / dt;
+            acceleration.last_velocity = velocity.0;
+        }
+
+        let t = match *color_mode {
+            ColorMode::Uniform => {
+                color.0 = if leader { LEADER_COLOR } else { Color::WHITE };
                 continue;
-            };
-            let Some(other_velocity) = velocity.get(&key) else {
+            }
+            ColorMode::Heading => {
+                velocity
+                    .0
+                    .y
+                    .atan2(velocity.0.x)
+                    .rem_euclid(std::f32::consts::TAU)
+                    / std::f32::consts::TAU
+            }
+            ColorMode::Speed => {
+                (velocity.0.length() - params.min_speed) / (params.max_speed - params.min_speed)
+            }
+            ColorMode::Neighbors => steering.n_neighbors as f32 / COLOR_MAX_NEIGHBORS,
+            ColorMode::Species => leader as u8 as f32,
+            ColorMode::Acceleration => acceleration.value.length() / COLOR_MAX_ACCELERATION,
+            ColorMode::Cluster => {
+                let size = clusters
+                    .clustering
+                    .clusters
+                    .get(cluster.index)
+                    .map_or(1, |c| c.size);
+                color.0 = if size > 1 {
+                    categorical(cluster.flock.0 as usize)
+                } else {
+                    LONER_COLOR
+                };
                 continue;
-            };
-
-            let distance = transform.translation.xy().distance_squared(*other_position);
-            if distance < boid.avoid_radius * boid.avoid_radius {
-                separation_acc += transform.translation.xy() - other_position;
-            } else if distance < boid.align_radius * boid.align_radius {
-                alignment_acc += other_velocity;
-                position_acc += other_position;
-                n_neighbors += 1;
             }
-        }
-
-        boid.separation_accumulator = separation_acc.extend(0.);
-        boid.alignment_accumulator = alignment_acc.extend(0.);
-        boid.position_accumulator = position_acc.extend(0.);
-        boid.n_neighbors = n_neighbors;
+        };
+        color.0 = color_mode.scale(palette, t);
     }
 }
 
-fn boids_behavior(
-    mut q_boids: Query<(&mut Boid, &Transform)>,
-    q_boids_other: Query<(&Transform, &Velocity), With<Boid>>,
-    voxels: Res<VoxelHashMap>,
+/// `V` cycles the colour mode, `Shift+V` the palette.
+fn cycle_color_mode(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut color_mode: ResMut<ColorMode>,
+    mut palette: ResMut<ColorPalette>,
 ) {
-    for (mut boid, transform) in q_boids.iter_mut() {
-        let mut separation = Vec3::ZERO;
-        let mut alignment = Vec3::ZERO;
-        let mut position = Vec3::ZERO;
+    if !keys.just_pressed(KeyCode::KeyV) {
+        return;
+    }
+    if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        palette.0 = (palette.0 + 1) % PALETTES.len();
+    } else {
+        *color_mode = color_mode.next();
+    }
+}
 
-        let mut n_neighbors = 0;
+/// `T` cycles the trail mode, `Shift+T` tags or untags the selected boid.
+fn cycle_trail_mode(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    mut trail_mode: ResMut<TrailMode>,
+    q_selected: Query<(Entity, Has<Tagged>), With<Selected>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyT) {
+        return;
+    }
+    if !keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        *trail_mode = trail_mode.next();
+        info!("Trails: {:?}", *trail_mode);
+        return;
+    }
+    for (entity, tagged) in q_selected.iter() {
+        if tagged {
+            commands.entity(entity).remove::<Tagged>();
+        } else {
+            commands.entity(entity).insert(Tagged);
+        }
+    }
+}
 
-        let neighbors = voxels.get_neighbor_entities(transform.translation.xy());
+/// Give trails to the boids the trail mode asks for, take them from the rest, and sample
+/// positions every `params.trail_interval` seconds.
+fn record_trails(
+    mut commands: Commands,
+    time: Res<Time>,
+    params: Res<Params>,
+    trail_mode: Res<TrailMode>,
+    mut clock: ResMut<TrailClock>,
+    mut q_boids: Query<
+        (
+            Entity,
+            &Transform,
+            Option<&mut Trail>,
+            Has<Selected>,
+            Has<Tagged>,
+        ),
+        With<Boid>,
+    >,
+) {
+    clock.0 += time.delta_secs();
+    let sample = clock.0 >= params.trail_interval;
+    if sample {
+        clock.0 = 0.;
+    }
 
-        // for (other_transform, other_velocity) in q_boids_other.iter() {
-        for other_entity in neighbors {
-            let (other_transform, other_velocity) = q_boids_other.get(other_entity).unwrap();
-            // if boid == other_boid {
-            //     continue;
-            // }
-            // Separation
-            let distance = transform
-                .translation
-                .distance_squared(other_transform.translation);
-            if distance < boid.avoid_radius * boid.avoid_radius {
-                separation += transform.translation - other_transform.translation;
+    for (entity, transform, trail, selected, tagged) in q_boids.iter_mut() {
+        let wanted = match *trail_mode {
+            TrailMode::Off => false,
+            TrailMode::All => true,
+            TrailMode::Selected => selected,
+            TrailMode::Tagged => tagged,
+        };
+        match trail {
+            Some(mut trail) if wanted => {
+                if trail.capacity() != params.trail_length {
+                    *trail = Trail::new(params.trail_length);
+                }
+                if sample {
+                    trail.push(transform.translation.xy());
+                }
             }
-            // Alignment
-            else if distance < boid.align_radius * boid.align_radius {
-                alignment += other_velocity.0;
-                position += other_transform.translation;
-                n_neighbors += 1;
+            Some(_) => {
+                commands.entity(entity).remove::<Trail>();
             }
+            None if wanted => {
+                commands
+                    .entity(entity)
+                    .insert(Trail::new(params.trail_length));
+            }
+            None => {}
         }
+    }
+}
 
-        boid.separation_accumulator = separation;
-        boid.alignment_accumulator = alignment;
-        boid.position_accumulator = position;
-        boid.n_neighbors = n_neighbors;
-
-        // // Separation
-        // velocity.0 += separation * BOID_SEPARATION_FACTOR;
+/// Rebuild the trail mesh, fading each trail from its boid's colour.
+fn update_trail_mesh(
+    mut trail_mesh: ResMut<TrailMesh>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    assets: Res<BoidAssets>,
+    time: Res<Time>,
+    params: Res<Params>,
+    query: Query<(&Trail, &Transform, &BoidColor)>,
+) {
+    // Anything further than a boid can plausibly travel between samples is a wrap around a
+    // periodic boundary
+    let max_step =
+        2. * (params.max_speed + FLOW_STRENGTH) * params.trail_interval.max(time.delta_secs());
 
-        // if n_neighbors > 0 {
-        //     alignment /= n_neighbors as f32;
-        //     average /= n_neighbors as f32;
+    trail_mesh.clear();
+    for (trail, transform, color) in query.iter() {
+        trail_mesh.push(
+            trail,
+            transform.translation.xy(),
+            color.0.with_alpha(TRAIL_ALPHA).to_linear(),
+            max_step,
+        );
+    }
+    if let Some(mesh) = meshes.get_mut(&assets.trails) {
+        trail_mesh.write(mesh);
+    }
+}
 
-        //     // Alignment
-        //     let vel = velocity.0;
-        //     velocity.0 += (alignment - vel) * BOID_ALIGNMENT_FACTOR;
+fn spawn_legend(mut commands: Commands) {
+    let font = TextFont {
+        font_size: PANEL_FONT_SIZE,
+        ..default()
+    };
 
-        //     // Cohesion
-        //     velocity.0 += (average - transform.translation) * BOID_COHESION_FACTOR;
-        // }
-    }
+    commands
+        .spawn((
+            Legend,
+            Node {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(10.),
+                left: Val::Px(10.),
+                width: Val::Px(200.),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(4.),
+                padding: UiRect::all(Val::Px(8.)),
+                ..default()
+            },
+            BackgroundColor(Color::srgba(0., 0., 0., 0.6)),
+            Visibility::Hidden,
+        ))
+        .with_children(|legend| {
+            legend.spawn((LegendText::Title, Text::default(), font.clone()));
+            legend
+                .spawn(Node {
+                    height: Val::Px(10.),
+                    ..default()
+                })
+                .with_children(|bar| {
+                    for i in 0..LEGEND_SWATCHES {
+                        bar.spawn((
+                            LegendSwatch(i),
+                            Node {
+                                width: Val::Percent(100. / LEGEND_SWATCHES as f32),
+                                height: Val::Percent(100.),
+                                ..default()
+                            },
+                            BackgroundColor(Color::WHITE),
+                        ));
+                    }
+                });
+            legend
+                .spawn(Node {
+                    justify_content: JustifyContent::SpaceBetween,
+                    ..default()
+                })
+                .with_children(|labels| {
+                    labels.spawn((LegendText::Low, Text::default(), font.clone()));
+                    labels.spawn((LegendText::High, Text::default(), font.clone()));
+                });
+        });
 }
 
-fn move_boids(
-    time: Res<Time>,
-    mut query: Query<(Entity, &mut Boid, &mut Transform, &mut Velocity)>,
-    mut voxels: ResMut<VoxelHashMap>,
+/// Redraw the legend for the current colour mode and palette, hiding it in uniform mode.
+fn update_legend(
+    color_mode: Res<ColorMode>,
+    palette: Res<ColorPalette>,
+    params: Res<Params>,
+    mut q_legend: Query<&mut Visibility, With<Legend>>,
+    mut q_swatches: Query<(&LegendSwatch, &mut BackgroundColor)>,
+    mut q_text: Query<(&LegendText, &mut Text)>,
 ) {
-    for (entity, mut boid, mut transform, mut velocity) in query.iter_mut() {
-        // Separation
-        velocity.0 += boid.separation_accumulator * BOID_SEPARATION_FACTOR;
+    if !(color_mode.is_changed() || palette.is_changed() || params.is_changed()) {
+        return;
+    }
+    let Ok(mut visibility) = q_legend.get_single_mut() else {
+        return;
+    };
+    let Some((title, low, high)) = color_mode.legend(&params) else {
+        *visibility = Visibility::Hidden;
+        return;
+    };
+    *visibility = Visibility::Inherited;
 
-        let n_neighbors = boid.n_neighbors;
-        if n_neighbors > 0 {
-            boid.alignment_accumulator /= n_neighbors as f32;
-            boid.position_accumulator /= n_neighbors as f32;
+    let palette = PALETTES[palette.0];
+    for (swatch, mut color) in q_swatches.iter_mut() {
+        // Sample the middle of each segment
+        let t = (swatch.0 as f32 + 0.5) / LEGEND_SWATCHES as f32;
+        color.0 = color_mode.scale(palette, t);
+    }
+    for (kind, mut text) in q_text.iter_mut() {
+        text.0 = match kind {
+            LegendText::Title => title.to_string(),
+            LegendText::Low => low.clone(),
+            LegendText::High => high.clone(),
+        };
+    }
+}
 
-            // Alignment
-            let vel = velocity.0;
-            velocity.0 += (boid.alignment_accumulator - vel) * BOID_ALIGNMENT_FACTOR;
+fn spawn_metrics_panel(mut commands: Commands) {
+    let font = TextFont {
+        font_size: PANEL_FONT_SIZE,
+        ..default()
+    };
 
-            // Cohesion
-            velocity.0 +=
-                (boid.position_accumulator - transform.translation) * BOID_COHESION_FACTOR;
+    // No background, which would be drawn over the gizmo lines
+    commands
+        .spawn((
+            MetricsPanel,
+            Node {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(50.),
+                right: Val::Px(10.),
+                width: Val::Px(PLOT_WIDTH),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(4.),
+                ..default()
+            },
+        ))
+        .with_children(|panel| {
+            for chart in MetricsChart::ALL {
+                panel.spawn((
+                    MetricsChartLabel(chart),
+                    Text::default(),
+                    font.clone(),
+                    TextColor(chart.color()),
+                ));
+                panel.spawn((
+                    chart,
+                    Node {
+                        height: Val::Px(PLOT_HEIGHT),
+                        ..default()
+                    },
+                ));
+            }
+        });
+}
+
+fn toggle_metrics_panel(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut q_panel: Query<&mut Visibility, With<MetricsPanel>>,
+) {
+    if keys.just_pressed(KeyCode::KeyM) {
+        for mut visibility in q_panel.iter_mut() {
+            visibility.toggle_visible_hidden();
         }
+    }
+}
 
-        // Reset values
-        boid.separation_accumulator = Vec3::ZERO;
-        boid.alignment_accumulator = Vec3::ZERO;
-        boid.position_accumulator = Vec3::ZERO;
-        boid.n_neighbors = 0;
+fn update_metrics_panel(
+    plot: Res<MetricsPlot>,
+    mut q_labels: Query<(&MetricsChartLabel, &mut Text)>,
+) {
+    if !plot.is_changed() {
+        return;
+    }
+    for (label, mut text) in q_labels.iter_mut() {
+        let value = label.0.series(&plot).latest().unwrap_or(f32::NAN);
+        text.0 = label.0.label(value);
+    }
+}
 
-        // Cap the velocity
-        if velocity.0.length() > BOID_MAX_SPEED {
-            velocity.0 = velocity.0.normalize() * BOID_MAX_SPEED;
+fn draw_metrics_plot(
+    mut gizmos: Gizmos,
+    plot: Res<MetricsPlot>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    q_charts: Query<(
+        &MetricsChart,
+        &ComputedNode,
+        &GlobalTransform,
+        &InheritedVisibility,
+    )>,
+) {
+    let Ok(camera) = q_camera.get_single() else {
+        return;
+    };
+    for (chart, node, transform, visibility) in q_charts.iter() {
+        if visibility.get() {
+            let (low, high) = chart.range(&plot);
+            let points = chart.series(&plot).normalized_points(low, high);
+            draw_chart(&mut gizmos, camera, node, transform, points, chart.color());
         }
+    }
+}
 
-        if velocity.0.length() < BOID_MIN_SPEED {
-            velocity.0 = velocity.0.normalize() * BOID_MIN_SPEED;
-        }
+/// Draw a chart's frame, and a line through `points` in the unit square, over its node,
+/// converting from the UI's physical pixels to the world through the camera.
+fn draw_chart(
+    gizmos: &mut Gizmos,
+    (camera, camera_transform): (&Camera, &GlobalTransform),
+    node: &ComputedNode,
+    transform: &GlobalTransform,
+    points: impl IntoIterator<Item = Vec2>,
+    color: Color,
+) {
+    let scale = node.inverse_scale_factor();
+    let size = node.size() * scale;
+    let bottom_left = transform.translation().xy() * scale + Vec2::new(-0.5, 0.5) * size;
+    // Viewport y points down, chart y up
+    let to_world = |point: Vec2| {
+        camera
+            .viewport_to_world_2d(
+                camera_transform,
+                bottom_left + Vec2::new(point.x, -point.y) * size,
+            )
+            .ok()
+    };
 
-        let old_translation = transform.translation.xy();
-        transform.translation += velocity.0 * time.delta_secs();
-        voxels.update_entity(old_translation, transform.translation.xy(), entity);
+    let Some(corners) = [Vec2::ZERO, Vec2::X, Vec2::ONE, Vec2::Y, Vec2::ZERO]
+        .into_iter()
+        .map(to_world)
+        .collect::<Option<Vec<_>>>()
+    else {
+        return;
+    };
+    gizmos.linestrip_2d(corners, Color::srgba(1., 1., 1., 0.3));
+    gizmos.linestrip_2d(points.into_iter().filter_map(to_world), color);
+}
 
-        // Rotate to face the direction of the velocity vector
-        let angle = ops::atan2(velocity.0.y, velocity.0.x);
-        transform.rotation = Quat::from_rotation_z(angle + std::f32::consts::FRAC_PI_2);
+fn spawn_correlation_panel(mut commands: Commands) {
+    let font = TextFont {
+        font_size: PANEL_FONT_SIZE,
+        ..default()
+    };
+
+    // Beside the metrics plots, again without a background
+    commands
+        .spawn((
+            CorrelationPanel,
+            Node {
+                position_type: PositionType::Absolute,
+                bottom: Val::Px(50.),
+                right: Val::Px(PLOT_WIDTH + 30.),
+                width: Val::Px(PLOT_WIDTH),
+                flex_direction: FlexDirection::Column,
+                row_gap: Val::Px(4.),
+                ..default()
+            },
+            Visibility::Hidden,
+        ))
+        .with_children(|panel| {
+            for chart in CorrelationChart::ALL {
+                panel.spawn((
+                    CorrelationChartLabel(chart),
+                    Text::default(),
+                    font.clone(),
+                    TextColor(chart.color()),
+                ));
+                panel.spawn((
+                    chart,
+                    Node {
+                        height: Val::Px(PLOT_HEIGHT),
+                        ..default()
+                    },
+                ));
+            }
+        });
+}
+
+/// Show or hide the correlation plots, starting their average afresh when shown.
+fn toggle_correlation_panel(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut analysis: ResMut<CorrelationAnalysis>,
+    mut q_panel: Query<&mut Visibility, With<CorrelationPanel>>,
+) {
+    if !keys.just_pressed(KeyCode::KeyK) {
+        return;
+    }
+    analysis.plot = match analysis.plot {
+        Some(_) => None,
+        None => Some(Correlations::new(CORRELATION_MAX_RADIUS, CORRELATION_BINS)),
+    };
+    for mut visibility in q_panel.iter_mut() {
+        *visibility = if analysis.plot.is_some() {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
     }
 }
 
-fn color_boids(
-    mut query: Query<(&Velocity, &MeshMaterial2d<ColorMaterial>), With<Boid>>,
-    mut materials: ResMut<Assets<ColorMaterial>>,
+fn update_correlation_panel(
+    analysis: Res<CorrelationAnalysis>,
+    mut q_labels: Query<(&CorrelationChartLabel, &mut Text)>,
+) {
+    let Some(correlations) = analysis.plot.as_ref().filter(|_| analysis.is_changed()) else {
+        return;
+    };
+    for (label, mut text) in q_labels.iter_mut() {
+        text.0 = label.0.label(correlations);
+    }
+}
+
+fn draw_correlation_plot(
+    mut gizmos: Gizmos,
+    analysis: Res<CorrelationAnalysis>,
+    q_camera: Query<(&Camera, &GlobalTransform)>,
+    q_charts: Query<(
+        &CorrelationChart,
+        &ComputedNode,
+        &GlobalTransform,
+        &InheritedVisibility,
+    )>,
 ) {
-    // let u = (Vec3::new(230., 132., 96.) / 255.).normalize();
-    let u = (Vec3::new(96., 230., 125.) / 255.).normalize();
-    let v = (Vec3::new(96., 181., 230.) / 255.).normalize();
-    let w = (Vec3::new(1., 0., 0.)).normalize();
-    // let w = (Vec3::new(230., 132., 96.) / 255.).normalize();
-
-    for (velocity, material) in query.iter_mut() {
-        let v_norm = (velocity.0.normalize() + 1.) / 2.;
-        // let hue = 360. * (velocity.0.length() - BOID_MIN_SPEED) / (BOID_MAX_SPEED - BOID_MIN_SPEED);
-        // let saturation = (v_norm.x + 1.) / 2.;
-        // let lightness = (v_norm.y + 1.) / 2.;
-        // let color = Color::hsl(hue, saturation, lightness);
-        let c = v_norm.x * u
-            + v_norm.y * v
-            + 0.3
-                * (1. - (velocity.0.length() - BOID_MIN_SPEED) / (BOID_MAX_SPEED - BOID_MIN_SPEED))
-                * w;
-        // let color = Color::xyz(
-        //     (v_norm.x + 1.) / 2.,
-        //     (v_norm.y + 1.) / 2.,
-        //     (velocity.0.length() - BOID_MIN_SPEED) / (BOID_MAX_SPEED - BOID_MIN_SPEED),
-        // );
-        let color = Color::linear_rgb(c.x, c.y, c.z);
-        let color_mat = materials.get_mut(material).unwrap();
-        color_mat.color = color;
+    let (Ok(camera), Some(correlations)) = (q_camera.get_single(), &analysis.plot) else {
+        return;
+    };
+    for (chart, node, transform, visibility) in q_charts.iter() {
+        if !visibility.get() {
+            continue;
+        }
+        let values = chart.values(correlations);
+        let (low, high) = chart.range(&values);
+        let bins = values.len() as f32;
+        let points = values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| !value.is_nan())
+            .map(|(bin, value)| {
+                Vec2::new(
+                    (bin as f32 + 0.5) / bins,
+                    ((value - low) / (high - low)).clamp(0., 1.),
+                )
+            });
+        draw_chart(&mut gizmos, camera, node, transform, points, chart.color());
     }
 }
 
+/// Fill the flock's instances from every boid's transform and colour.
+fn update_flock_instances(
+    mut q_flock: Query<&mut FlockInstances>,
+    query: Query<(&Transform, &BoidColor)>,
+) {
+    let Ok(mut instances) = q_flock.get_single_mut() else {
+        return;
+    };
+    instances.0.clear();
+    instances.0.extend(
+        query
+            .iter()
+            .map(|(transform, color)| FlockInstance::new(transform, color.0.to_linear())),
+    );
+}
+
 // fn rotate_boids(mut query: Query<(&Boid, &mut Transform, &Velocity)>) {
 //     for (_, mut transform, velocity) in query.iter_mut() {
 //         // Rotate to face the direction of the velocity vector
@@ -339,51 +4167,67 @@ fn color_boids(
 //     }
 // }
 
+/// Half extents of the world around the origin: the window, or 800 by 800 without one.
+fn world_bounds(window: Option<&Window>) -> Vec2 {
+    window.map_or(Vec2::splat(400.), |window| window.size() / 2.)
+}
+
 fn avoid_boundary(
-    mut query: Query<(&mut Velocity, &Transform), With<Boid>>,
+    mut query: Query<(&mut Velocity, &mut Steering, &Transform), With<Boid>>,
     window_q: Query<&Window, With<PrimaryWindow>>,
+    params: Res<Params>,
 ) {
-    let (width, height) = match window_q.get_single() {
-        Ok(window) => (
-            window.width() / 2. - 5. * BOID_RADIUS,
-            window.height() / 2. - 5. * BOID_RADIUS,
-        ),
-        _ => (400., 400.),
-    };
+    if params.boundary != BoundaryMode::Avoid {
+        for (_, mut steering, _) in query.iter_mut() {
+            steering.boundary = Vec3::ZERO;
+        }
+        return;
+    }
+
+    let Vec2 {
+        x: width,
+        y: height,
+    } = world_bounds(window_q.get_single().ok()) - 5. * BOID_RADIUS;
 
-    for (mut velocity, transform) in query.iter_mut() {
+    for (mut velocity, mut steering, transform) in query.iter_mut() {
         let x = transform.translation.x;
         let y = transform.translation.y;
 
+        let mut turn = Vec3::ZERO;
         if x > width {
-            velocity.0.x -= BOID_TURN_FACTOR;
+            turn.x -= params.turn_factor;
         }
         if x < -width {
-            velocity.0.x += BOID_TURN_FACTOR;
+            turn.x += params.turn_factor;
         }
         if y > height {
-            velocity.0.y -= BOID_TURN_FACTOR;
+            turn.y -= params.turn_factor;
         }
         if y < -height {
-            velocity.0.y += BOID_TURN_FACTOR;
+            turn.y += params.turn_factor;
         }
+        steering.boundary = turn;
+        velocity.0 += turn;
     }
 }
 
-#[allow(dead_code)]
 fn periodic_boundary(
-    mut query: Query<&mut Transform, With<Boid>>,
+    mut query: Query<(Entity, &mut Transform), With<Boid>>,
     window_q: Query<&Window, With<PrimaryWindow>>,
+    mut voxels: ResMut<VoxelHashMap>,
+    params: Res<Params>,
 ) {
-    let (width, height) = match window_q.get_single() {
-        Ok(window) => (
-            window.width() / 2. + BOID_RADIUS,
-            window.height() / 2. + BOID_RADIUS,
-        ),
-        _ => (400., 400.),
-    };
+    if params.boundary != BoundaryMode::Periodic {
+        return;
+    }
+
+    let Vec2 {
+        x: width,
+        y: height,
+    } = world_bounds(window_q.get_single().ok()) + BOID_RADIUS;
 
-    for mut transform in query.iter_mut() {
+    for (entity, mut transform) in query.iter_mut() {
+        let old_translation = transform.translation.xy();
         if transform.translation.x > width {
             transform.translation.x = -width;
         }
@@ -396,5 +4240,6 @@ fn periodic_boundary(
         if transform.translation.y < -height {
             transform.translation.y = height;
         }
+        voxels.update_entity(old_translation, transform.translation.xy(), entity);
     }
 }
@@ -0,0 +1,166 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::voxel::VoxelHashMap;
+
+/// Standard observables of collective motion, as in Couzin et al. 2002 ("Collective memory and
+/// spatial sorting in animal groups") and Tunstrøm et al. 2013.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FlockMetrics {
+    /// Length of the mean heading: 1 when every boid heads the same way, near 0 when disordered
+    pub polarization: f32,
+    /// Rotational order, the length of the mean angular momentum of unit headings about the
+    /// centroid: 1 for a perfect mill
+    pub milling: f32,
+    /// Root mean square distance from the centroid
+    pub group_radius: f32,
+    pub mean_speed: f32,
+}
+
+impl FlockMetrics {
+    /// Metrics of `boids` (entity, position and velocity), or `None` without any boids.
+    pub fn new(boids: &[(Entity, Vec2, Vec2)]) -> Option<Self> {
+        if boids.is_empty() {
+            return None;
+        }
+        let n = boids.len() as f32;
+        let centroid = boids.iter().map(|(_, position, _)| *position).sum::<Vec2>() / n;
+
+        let mut heading_sum = Vec2::ZERO;
+        let mut angular_momentum = 0.;
+        let mut squared_radius = 0.;
+        let mut speed = 0.;
+        for (_, position, velocity) in boids {
+            let heading = velocity.normalize_or_zero();
+            let offset = *position - centroid;
+            heading_sum += heading;
+            angular_momentum += offset.normalize_or_zero().perp_dot(heading);
+            squared_radius += offset.length_squared();
+            speed += velocity.length();
+        }
+        Some(Self {
+            polarization: heading_sum.length() / n,
+            milling: angular_momentum.abs() / n,
+            group_radius: (squared_radius / n).sqrt(),
+            mean_speed: speed / n,
+        })
+    }
+}
+
+/// Mean distance from each boid (entity, position and velocity) to its nearest neighbour, found by
+/// searching rings of the cells of `voxels` outwards until no closer boid can remain. Entities
+/// in `voxels` that aren't in `boids` are ignored. `None` for fewer than two boids.
+pub fn mean_nearest_neighbor_distance(
+    boids: &[(Entity, Vec2, Vec2)],
+    voxels: &VoxelHashMap,
+) -> Option<f32> {
+    if boids.len() < 2 || voxels.cell_size <= 0. {
+        return None;
+    }
+    let positions = boids
+        .iter()
+        .map(|(entity, position, _)| (*entity, *position))
+        .collect::<HashMap<_, _>>();
+    // Rings past every occupied cell can't hold anything
+    let (min, max) = voxels.map.keys().fold(
+        ((i64::MAX, i64::MAX), (i64::MIN, i64::MIN)),
+        |(min, max), (x, y)| {
+            (
+                (min.0.min(*x), min.1.min(*y)),
+                (max.0.max(*x), max.1.max(*y)),
+            )
+        },
+    );
+
+    let mut total = 0.;
+    let mut found = 0;
+    for (entity, position, _) in boids {
+        let (cx, cy) = voxels.vec2_to_key(*position);
+        let last_ring = [cx - min.0, max.0 - cx, cy - min.1, max.1 - cy]
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+        let mut best = f32::INFINITY;
+        for ring in 0..=last_ring {
+            // Every boid in this ring or beyond is at least `ring - 1` cells away
+            if (ring - 1) as f32 * voxels.cell_size > best {
+                break;
+            }
+            for x in cx - ring..=cx + ring {
+                for y in cy - ring..=cy + ring {
+                    if (x - cx).abs() != ring && (y - cy).abs() != ring {
+                        continue;
+                    }
+                    for other in voxels.map.get(&(x, y)).into_iter().flatten() {
+                        if other == entity {
+                            continue;
+                        }
+                        if let Some(other) = positions.get(other) {
+                            best = best.min(position.distance(*other));
+                        }
+                    }
+                }
+            }
+        }
+        if best.is_finite() {
+            total += best;
+            found += 1;
+        }
+    }
+    (found > 0).then(|| total / found as f32)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::voxels;
+
+    #[test]
+    fn test_polarized_flock() {
+        let boids = [
+            (Entity::PLACEHOLDER, Vec2::new(0., 0.), Vec2::new(2., 0.)),
+            (Entity::PLACEHOLDER, Vec2::new(0., 2.), Vec2::new(4., 0.)),
+        ];
+        let metrics = FlockMetrics::new(&boids).unwrap();
+        assert_eq!(metrics.polarization, 1.);
+        assert_eq!(metrics.mean_speed, 3.);
+        assert_eq!(metrics.group_radius, 1.);
+        // Moving in a line, with no net rotation about the centroid
+        assert!(metrics.milling.abs() < 1e-6);
+        assert!(FlockMetrics::new(&[]).is_none());
+    }
+
+    #[test]
+    fn test_mill() {
+        // Four boids circling the origin anticlockwise
+        let boids = [Vec2::X, Vec2::Y, Vec2::NEG_X, Vec2::NEG_Y]
+            .map(|p| (Entity::PLACEHOLDER, p * 5., p.perp()));
+        let metrics = FlockMetrics::new(&boids).unwrap();
+        assert!(metrics.polarization < 1e-6);
+        assert!((metrics.milling - 1.).abs() < 1e-6);
+        assert!((metrics.group_radius - 5.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_mean_nearest_neighbor_distance() {
+        let boids = [
+            Vec2::new(0., 0.),
+            Vec2::new(3., 0.),
+            // Several cells away from the others
+            Vec2::new(100., 0.),
+        ]
+        .map(|position| (Entity::from_raw(position.x as u32), position, Vec2::ZERO));
+        // Nearest distances are 3, 3 and 97
+        let mean = mean_nearest_neighbor_distance(&boids, &voxels(&boids, 2.)).unwrap();
+        assert!((mean - 103. / 3.).abs() < 1e-4);
+        // The answer doesn't depend on the cell size
+        let mean_large = mean_nearest_neighbor_distance(&boids, &voxels(&boids, 500.)).unwrap();
+        assert!((mean - mean_large).abs() < 1e-4);
+        assert!(mean_nearest_neighbor_distance(&boids[..1], &voxels(&boids, 2.)).is_none());
+
+        // Boids missing from the map are never found as neighbours: without the first, the
+        // second is 97 from its nearest
+        let mean_subset = mean_nearest_neighbor_distance(&boids, &voxels(&boids[1..], 2.)).unwrap();
+        assert!((mean_subset - 197. / 3.).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,387 @@
+use std::fmt;
+use std::io::{self, Write};
+
+/// A numeric array read from a NumPy `.npy` file, converted to `f32` in C (row-major) order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NpyArray {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+#[derive(Debug)]
+pub enum NpyError {
+    /// The file does not start with the `.npy` magic string.
+    NotNpy,
+    /// The header could not be parsed.
+    Header(String),
+    /// The element type is not a little-endian float.
+    UnsupportedDtype(String),
+    /// The payload is shorter than the header's shape requires.
+    Truncated,
+}
+
+impl fmt::Display for NpyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NpyError::NotNpy => write!(f, "not a .npy file"),
+            NpyError::Header(header) => write!(f, "invalid .npy header: {header}"),
+            NpyError::UnsupportedDtype(dtype) => write!(f, "unsupported .npy dtype {dtype}"),
+            NpyError::Truncated => write!(f, ".npy data is truncated"),
+        }
+    }
+}
+
+impl std::error::Error for NpyError {}
+
+const MAGIC: &[u8] = b"\x93NUMPY";
+
+/// Parse a `.npy` file holding `<f4` or `<f8` data.
+pub fn read_npy(bytes: &[u8]) -> Result<NpyArray, NpyError> {
+    if bytes.len() < 10 || &bytes[..6] != MAGIC {
+        return Err(NpyError::NotNpy);
+    }
+
+    // Version 1 uses a 2-byte header length, versions 2 and 3 a 4-byte one
+    let (header_len, offset) = match bytes[6] {
+        1 => (u16::from_le_bytes([bytes[8], bytes[9]]) as usize, 10),
+        2 | 3 if bytes.len() >= 12 => (
+            u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize,
+            12,
+        ),
+        _ => return Err(NpyError::NotNpy),
+    };
+    let header = bytes
+        .get(offset..offset + header_len)
+        .and_then(|header| std::str::from_utf8(header).ok())
+        .ok_or(NpyError::Truncated)?;
+
+    let descr = header_value(header, "descr")
+        .map(|descr| descr.trim_matches(|c| c == '\'' || c == '"'))
+        .ok_or_else(|| NpyError::Header(header.to_string()))?;
+    if header_value(header, "fortran_order") != Some("False") {
+        return Err(NpyError::Header(header.to_string()));
+    }
+    let shape = header_value(header, "shape")
+        .and_then(parse_shape)
+        .ok_or_else(|| NpyError::Header(header.to_string()))?;
+
+    let payload = &bytes[offset + header_len..];
+    let len = shape.iter().product::<usize>();
+    let data = match descr {
+        "<f4" => payload
+            .chunks_exact(4)
+            .take(len)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>(),
+        "<f8" => payload
+            .chunks_exact(8)
+            .take(len)
+            .map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32)
+            .collect::<Vec<_>>(),
+        _ => return Err(NpyError::UnsupportedDtype(descr.to_string())),
+    };
+    if data.len() != len {
+        return Err(NpyError::Truncated);
+    }
+
+    Ok(NpyArray { shape, data })
+}
+
+/// Element types that can be written to `.npy` files.
+pub trait NpyElement: Copy {
+    /// NumPy dtype string
+    const DESCR: &'static str;
+    fn extend_le(self, bytes: &mut Vec<u8>);
+}
+
+impl NpyElement for f32 {
+    const DESCR: &'static str = "<f4";
+    fn extend_le(self, bytes: &mut Vec<u8>) {
+        bytes.extend(self.to_le_bytes());
+    }
+}
+
+impl NpyElement for f64 {
+    const DESCR: &'static str = "<f8";
+    fn extend_le(self, bytes: &mut Vec<u8>) {
+        bytes.extend(self.to_le_bytes());
+    }
+}
+
+impl NpyElement for u32 {
+    const DESCR: &'static str = "<u4";
+    fn extend_le(self, bytes: &mut Vec<u8>) {
+        bytes.extend(self.to_le_bytes());
+    }
+}
+
+impl NpyElement for u64 {
+    const DESCR: &'static str = "<u8";
+    fn extend_le(self, bytes: &mut Vec<u8>) {
+        bytes.extend(self.to_le_bytes());
+    }
+}
+
+/// Encode `data`, in C (row-major) order, as a version 1 `.npy` file of the given shape.
+pub fn write_npy<T: NpyElement>(shape: &[usize], data: &[T]) -> Vec<u8> {
+    assert_eq!(
+        shape.iter().product::<usize>(),
+        data.len(),
+        "shape {shape:?} doesn't match {} elements",
+        data.len()
+    );
+    let dims = match shape {
+        [n] => format!("({n},)"),
+        _ => format!(
+            "({})",
+            shape
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {dims}, }}",
+        T::DESCR
+    );
+    // Pad so the data is 64-byte aligned, terminated by a newline
+    while !(MAGIC.len() + 4 + header.len() + 1).is_multiple_of(64) {
+        header.push(' ');
+    }
+    header.push('\n');
+
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + header.len() + data.len() * 8);
+    bytes.extend(MAGIC);
+    bytes.extend([1, 0]);
+    bytes.extend((header.len() as u16).to_le_bytes());
+    bytes.extend(header.as_bytes());
+    for value in data {
+        value.extend_le(&mut bytes);
+    }
+    bytes
+}
+
+/// Write `.npy` files into an uncompressed `.npz` archive, which is a zip file. Names get a
+/// `.npy` suffix like `np.savez`, so they load under the plain name. Without Zip64, each file
+/// and the whole archive must be under 4 GiB.
+pub fn write_npz(out: &mut impl Write, files: &[(&str, &[u8])]) -> io::Result<()> {
+    let too_large = || io::Error::new(io::ErrorKind::InvalidInput, ".npz archive over 4 GiB");
+    // 1980-01-01, the earliest date a zip file can hold
+    const DOS_DATE: u16 = (1 << 5) | 1;
+
+    let mut central = Vec::new();
+    let mut offset = 0u32;
+    for (name, data) in files {
+        let name = format!("{name}.npy");
+        let size = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let crc = crc32(data);
+
+        let mut local = Vec::with_capacity(30 + name.len());
+        local.extend(0x04034b50u32.to_le_bytes());
+        // Version needed, flags, stored, time, date
+        for field in [20u16, 0, 0, 0, DOS_DATE] {
+            local.extend(field.to_le_bytes());
+        }
+        for field in [crc, size, size] {
+            local.extend(field.to_le_bytes());
+        }
+        local.extend((name.len() as u16).to_le_bytes());
+        local.extend(0u16.to_le_bytes());
+        local.extend(name.as_bytes());
+        out.write_all(&local)?;
+        out.write_all(data)?;
+
+        central.extend(0x02014b50u32.to_le_bytes());
+        // Version made by, version needed, flags, stored, time, date
+        for field in [20u16, 20, 0, 0, 0, DOS_DATE] {
+            central.extend(field.to_le_bytes());
+        }
+        for field in [crc, size, size] {
+            central.extend(field.to_le_bytes());
+        }
+        // Name, extra field and comment lengths, disk, internal and external attributes
+        for field in [name.len() as u16, 0, 0, 0, 0] {
+            central.extend(field.to_le_bytes());
+        }
+        central.extend(0u32.to_le_bytes());
+        central.extend(offset.to_le_bytes());
+        central.extend(name.as_bytes());
+
+        offset = (local.len() as u32)
+            .checked_add(size)
+            .and_then(|len| offset.checked_add(len))
+            .ok_or_else(too_large)?;
+    }
+
+    let count = files.len() as u16;
+    let mut end = Vec::with_capacity(22);
+    end.extend(0x06054b50u32.to_le_bytes());
+    for field in [0u16, 0, count, count] {
+        end.extend(field.to_le_bytes());
+    }
+    end.extend((central.len() as u32).to_le_bytes());
+    end.extend(offset.to_le_bytes());
+    end.extend(0u16.to_le_bytes());
+    out.write_all(&central)?;
+    out.write_all(&end)
+}
+
+/// CRC-32 (IEEE) as used by zip.
+fn crc32(bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut c = i as u32;
+            let mut k = 0;
+            while k < 8 {
+                c = if c & 1 != 0 {
+                    0xedb88320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+                k += 1;
+            }
+            table[i] = c;
+            i += 1;
+        }
+        table
+    };
+    !bytes.iter().fold(!0u32, |crc, byte| {
+        TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Raw text of `key`'s value in the header's Python dict literal.
+fn header_value<'a>(header: &'a str, key: &str) -> Option<&'a str> {
+    let start = header.find(&format!("'{key}'"))? + key.len() + 2;
+    let rest = header[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = if rest.starts_with('(') {
+        rest.find(')')? + 1
+    } else {
+        rest.find(',').unwrap_or(rest.len())
+    };
+    Some(rest[..end].trim())
+}
+
+fn parse_shape(shape: &str) -> Option<Vec<usize>> {
+    shape
+        .strip_prefix('(')?
+        .strip_suffix(')')?
+        .split(',')
+        .map(str::trim)
+        .filter(|dim| !dim.is_empty())
+        .map(|dim| dim.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn npy_bytes(descr: &str, shape: &str, payload: &[u8]) -> Vec<u8> {
+        let mut header =
+            format!("{{'descr': '{descr}', 'fortran_order': False, 'shape': {shape}, }}");
+        // Pad so the payload is 64-byte aligned, terminated by a newline
+        while (10 + header.len() + 1) % 64 != 0 {
+            header.push(' ');
+        }
+        header.push('\n');
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend([1, 0]);
+        bytes.extend((header.len() as u16).to_le_bytes());
+        bytes.extend(header.as_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    #[test]
+    fn test_read_f4() {
+        let payload: Vec<u8> = [1f32, 2., 3., 4., 5., 6.]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let array = read_npy(&npy_bytes("<f4", "(3, 2)", &payload)).unwrap();
+        assert_eq!(array.shape, vec![3, 2]);
+        assert_eq!(array.data, vec![1., 2., 3., 4., 5., 6.]);
+    }
+
+    #[test]
+    fn test_read_f8() {
+        let payload: Vec<u8> = [0.5f64, -1.5]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let array = read_npy(&npy_bytes("<f8", "(2,)", &payload)).unwrap();
+        assert_eq!(array.shape, vec![2]);
+        assert_eq!(array.data, vec![0.5, -1.5]);
+    }
+
+    #[test]
+    fn test_write_round_trip() {
+        let bytes = write_npy(&[2, 3], &[1f32, 2., 3., 4., 5., f32::NAN]);
+        // The header is padded so the data starts 64-byte aligned
+        let data_start = 10 + u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!(data_start % 64, 0);
+        assert_eq!(bytes.len(), data_start + 24);
+        let array = read_npy(&bytes).unwrap();
+        assert_eq!(array.shape, vec![2, 3]);
+        assert_eq!(array.data[..5], [1., 2., 3., 4., 5.]);
+        assert!(array.data[5].is_nan());
+
+        let bytes = write_npy(&[2], &[7u64, 8]);
+        let data_start = bytes.len() - 16;
+        assert!(std::str::from_utf8(&bytes[10..data_start])
+            .unwrap()
+            .contains("'descr': '<u8', 'fortran_order': False, 'shape': (2,)"));
+        assert_eq!(bytes[data_start..data_start + 8], 7u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_write_npz() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+
+        let a = write_npy(&[1], &[1f32]);
+        let b = write_npy(&[2], &[2f32, 3.]);
+        let mut zip = Vec::new();
+        write_npz(&mut zip, &[("a", &a), ("bee", &b)]).unwrap();
+
+        // The end record points at two central directory entries
+        let end = &zip[zip.len() - 22..];
+        assert_eq!(end[..4], 0x06054b50u32.to_le_bytes());
+        assert_eq!(u16::from_le_bytes([end[10], end[11]]), 2);
+        let central = u32::from_le_bytes(end[16..20].try_into().unwrap()) as usize;
+        assert_eq!(zip[central..central + 4], 0x02014b50u32.to_le_bytes());
+
+        // Each local entry holds a stored .npy file
+        assert_eq!(zip[..4], 0x04034b50u32.to_le_bytes());
+        assert_eq!(&zip[30..35], b"a.npy");
+        assert_eq!(read_npy(&zip[35..35 + a.len()]).unwrap().data, vec![1.]);
+        let second = 35 + a.len();
+        assert_eq!(&zip[second + 30..second + 37], b"bee.npy");
+        assert_eq!(
+            read_npy(&zip[second + 37..second + 37 + b.len()])
+                .unwrap()
+                .data,
+            vec![2., 3.]
+        );
+    }
+
+    #[test]
+    fn test_read_errors() {
+        assert!(matches!(
+            read_npy(b"not a numpy file"),
+            Err(NpyError::NotNpy)
+        ));
+        assert!(matches!(
+            read_npy(&npy_bytes("<i4", "(1,)", &[0; 4])),
+            Err(NpyError::UnsupportedDtype(_))
+        ));
+        assert!(matches!(
+            read_npy(&npy_bytes("<f4", "(4,)", &[0; 8])),
+            Err(NpyError::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+use bevy::prelude::*;
+
+/// A colour map from `[0, 1]`, defined by evenly spaced sRGB stops with linear interpolation
+/// between them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub name: &'static str,
+    pub stops: &'static [[f32; 3]],
+    /// Wrap around instead of clamping, for angles.
+    pub cyclic: bool,
+}
+
+impl Palette {
+    pub const VIRIDIS: Palette = Palette {
+        name: "Viridis",
+        stops: &[
+            [0.267, 0.005, 0.329],
+            [0.283, 0.141, 0.458],
+            [0.254, 0.265, 0.530],
+            [0.207, 0.372, 0.553],
+            [0.164, 0.471, 0.558],
+            [0.128, 0.567, 0.551],
+            [0.135, 0.659, 0.518],
+            [0.267, 0.749, 0.441],
+            [0.478, 0.821, 0.319],
+            [0.741, 0.873, 0.150],
+            [0.993, 0.906, 0.144],
+        ],
+        cyclic: false,
+    };
+
+    pub const MAGMA: Palette = Palette {
+        name: "Magma",
+        stops: &[
+            [0.001, 0.000, 0.014],
+            [0.078, 0.054, 0.211],
+            [0.232, 0.060, 0.437],
+            [0.390, 0.100, 0.502],
+            [0.550, 0.161, 0.506],
+            [0.716, 0.215, 0.475],
+            [0.868, 0.288, 0.409],
+            [0.967, 0.439, 0.360],
+            [0.994, 0.624, 0.427],
+            [0.997, 0.812, 0.572],
+            [0.987, 0.991, 0.750],
+        ],
+        cyclic: false,
+    };
+
+    pub const COOL_WARM: Palette = Palette {
+        name: "Cool-warm",
+        stops: &[
+            [0.230, 0.299, 0.754],
+            [0.552, 0.690, 0.996],
+            [0.866, 0.866, 0.866],
+            [0.958, 0.604, 0.482],
+            [0.706, 0.016, 0.150],
+        ],
+        cyclic: false,
+    };
+
+    pub const GRAYSCALE: Palette = Palette {
+        name: "Grayscale",
+        stops: &[[0.2, 0.2, 0.2], [1., 1., 1.]],
+        cyclic: false,
+    };
+
+    /// Fully saturated hue wheel, starting and ending at red.
+    pub const HUE: Palette = Palette {
+        name: "Hue",
+        stops: &[
+            [1., 0., 0.],
+            [1., 1., 0.],
+            [0., 1., 0.],
+            [0., 1., 1.],
+            [0., 0., 1.],
+            [1., 0., 1.],
+        ],
+        cyclic: true,
+    };
+
+    /// Colour at `t`, clamped to `[0, 1]` or wrapped if the palette is cyclic.
+    pub fn sample(&self, t: f32) -> Color {
+        let n = self.stops.len();
+        if n == 0 {
+            return Color::WHITE;
+        }
+        let t = if t.is_nan() {
+            0.
+        } else if self.cyclic {
+            t.rem_euclid(1.)
+        } else {
+            t.clamp(0., 1.)
+        };
+
+        // A cyclic palette interpolates from the last stop back to the first
+        let segments = if self.cyclic { n } else { n - 1 };
+        let x = t * segments as f32;
+        let i = (x.floor() as usize).min(segments.saturating_sub(1));
+        let a = Vec3::from(self.stops[i % n]);
+        let b = Vec3::from(self.stops[(i + 1) % n]);
+        let c = a.lerp(b, x - i as f32);
+        Color::srgb(c.x, c.y, c.z)
+    }
+}
+
+/// Distinct colours for integer labels, spacing hues by the golden angle.
+pub fn categorical(index: usize) -> Color {
+    let hue = (index as f32 * 137.507_77).rem_euclid(360.);
+    Color::hsl(hue, 0.75, 0.6)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn srgb(color: Color) -> [f32; 3] {
+        let c = color.to_srgba();
+        [c.red, c.green, c.blue]
+    }
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        assert!(
+            Vec3::from(a).abs_diff_eq(Vec3::from(b), 1e-5),
+            "{a:?} != {b:?}"
+        );
+    }
+
+    #[test]
+    fn test_sample_clamps() {
+        let palette = Palette::GRAYSCALE;
+        assert_close(srgb(palette.sample(0.)), [0.2, 0.2, 0.2]);
+        assert_close(srgb(palette.sample(1.)), [1., 1., 1.]);
+        assert_close(srgb(palette.sample(0.5)), [0.6, 0.6, 0.6]);
+        assert_close(srgb(palette.sample(-3.)), [0.2, 0.2, 0.2]);
+        assert_close(srgb(palette.sample(7.)), [1., 1., 1.]);
+        assert_close(srgb(palette.sample(f32::NAN)), [0.2, 0.2, 0.2]);
+    }
+
+    #[test]
+    fn test_sample_cyclic() {
+        let palette = Palette::HUE;
+        assert_close(srgb(palette.sample(0.)), [1., 0., 0.]);
+        assert_close(srgb(palette.sample(1.)), [1., 0., 0.]);
+        assert_close(srgb(palette.sample(1. / 3.)), [0., 1., 0.]);
+        assert_close(srgb(palette.sample(-2. / 3.)), [0., 1., 0.]);
+        // Between the last stop and the first
+        assert_close(srgb(palette.sample(11. / 12.)), [1., 0., 0.5]);
+    }
+
+    #[test]
+    fn test_categorical_distinct() {
+        let colors = (0..8).map(|i| srgb(categorical(i))).collect::<Vec<_>>();
+        for (i, a) in colors.iter().enumerate() {
+            for b in &colors[i + 1..] {
+                assert!(Vec3::from(*a).distance(Vec3::from(*b)) > 0.05);
+            }
+        }
+    }
+}
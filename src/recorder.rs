@@ -0,0 +1,618 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::npy;
+
+#[cfg(feature = "arrow")]
+use crate::columnar::{trajectory_batch, trajectory_schema, BatchWriter};
+
+/// One boid at one tick.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct TrajectoryRow {
+    pub tick: u64,
+    pub id: u64,
+    pub x: f32,
+    pub y: f32,
+    pub vx: f32,
+    pub vy: f32,
+    pub n_neighbors: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrajectoryFormat {
+    /// Comma-separated values with a header line
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+    /// Arrow IPC file, with the `arrow` feature
+    Arrow,
+    /// Snappy-compressed Parquet, with the `parquet` feature
+    Parquet,
+    /// NumPy arrays, one `.npy` file each
+    Npy,
+    /// NumPy arrays in one uncompressed `.npz` archive
+    Npz,
+}
+
+impl TrajectoryFormat {
+    /// Guess the format from a file extension, defaulting to CSV.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ndjson" | "jsonl" | "json") => TrajectoryFormat::Ndjson,
+            Some("arrow" | "feather" | "ipc") => TrajectoryFormat::Arrow,
+            Some("parquet") => TrajectoryFormat::Parquet,
+            Some("npy") => TrajectoryFormat::Npy,
+            Some("npz") => TrajectoryFormat::Npz,
+            _ => TrajectoryFormat::Csv,
+        }
+    }
+
+    /// The cargo feature needed to read and write this format, if it wasn't enabled.
+    pub fn missing_feature(self) -> Option<&'static str> {
+        match self {
+            TrajectoryFormat::Arrow if cfg!(not(feature = "arrow")) => Some("arrow"),
+            TrajectoryFormat::Parquet if cfg!(not(feature = "parquet")) => Some("parquet"),
+            _ => None,
+        }
+    }
+}
+
+pub const CSV_HEADER: &str = "tick,id,x,y,vx,vy,n_neighbors";
+
+/// Rows per record batch in Arrow and Parquet files. Batches are only split between ticks.
+pub const BATCH_ROWS: usize = 1 << 16;
+
+/// A row type [`RowWriter`] can write in every [`TrajectoryFormat`].
+pub trait Row: Serialize + Copy {
+    /// First line of CSV files
+    const CSV_HEADER: &'static str;
+    /// Rows per record batch in Arrow and Parquet files
+    const BATCH_ROWS: usize;
+
+    fn tick(&self) -> u64;
+
+    /// Append the row to `line` as CSV, without a newline.
+    fn write_csv(&self, line: &mut String);
+
+    #[cfg(feature = "arrow")]
+    fn schema(metadata: HashMap<String, String>) -> arrow_schema::SchemaRef;
+
+    #[cfg(feature = "arrow")]
+    fn batch(
+        schema: arrow_schema::SchemaRef,
+        rows: &[Self],
+    ) -> Result<arrow_array::RecordBatch, arrow_schema::ArrowError>;
+
+    /// Write all of a file's rows as NumPy arrays.
+    fn write_arrays(path: &Path, format: TrajectoryFormat, rows: &[Self]) -> io::Result<()>;
+}
+
+impl Row for TrajectoryRow {
+    const CSV_HEADER: &'static str = CSV_HEADER;
+    const BATCH_ROWS: usize = BATCH_ROWS;
+
+    fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    fn write_csv(&self, line: &mut String) {
+        let TrajectoryRow {
+            tick,
+            id,
+            x,
+            y,
+            vx,
+            vy,
+            n_neighbors,
+        } = self;
+        let _ = write!(line, "{tick},{id},{x},{y},{vx},{vy},{n_neighbors}");
+    }
+
+    #[cfg(feature = "arrow")]
+    fn schema(metadata: HashMap<String, String>) -> arrow_schema::SchemaRef {
+        trajectory_schema(metadata)
+    }
+
+    #[cfg(feature = "arrow")]
+    fn batch(
+        schema: arrow_schema::SchemaRef,
+        rows: &[Self],
+    ) -> Result<arrow_array::RecordBatch, arrow_schema::ArrowError> {
+        trajectory_batch(schema, rows)
+    }
+
+    fn write_arrays(path: &Path, format: TrajectoryFormat, rows: &[Self]) -> io::Result<()> {
+        write_arrays(path, format, rows)
+    }
+}
+
+/// Writes rows to `path`, or to numbered files `stem-0000.ext`, `stem-0001.ext`, ... when
+/// rotating every `rotate_ticks` ticks. Files are only split between ticks.
+///
+/// Arrow and Parquet files are only readable once [`RowWriter::finish`] has been called (or the
+/// writer dropped). NumPy arrays are kept in memory until then, since their shape comes first;
+/// rotate files to bound memory use.
+pub struct RowWriter<R: Row> {
+    path: PathBuf,
+    format: TrajectoryFormat,
+    rotate_ticks: Option<u64>,
+    /// Stored in the schema of Arrow and Parquet files
+    #[cfg_attr(not(feature = "arrow"), allow(dead_code))]
+    metadata: HashMap<String, String>,
+    /// Index of the open file
+    file: Option<(u64, Sink<R>)>,
+    /// Set by [`RowWriter::finish`], after which rows are refused rather than truncating a file
+    finished: bool,
+    line: String,
+}
+
+/// Writes trajectory rows, see [`RowWriter`].
+pub type TrajectoryWriter = RowWriter<TrajectoryRow>;
+
+enum Sink<R> {
+    Text(BufWriter<File>),
+    #[cfg(feature = "arrow")]
+    Columnar {
+        writer: Box<BatchWriter>,
+        schema: arrow_schema::SchemaRef,
+        /// Rows not yet written as a batch
+        rows: Vec<R>,
+    },
+    Arrays {
+        path: PathBuf,
+        rows: Vec<R>,
+    },
+}
+
+impl<R: Row> RowWriter<R> {
+    pub fn new(
+        path: impl Into<PathBuf>,
+        format: TrajectoryFormat,
+        rotate_ticks: Option<u64>,
+    ) -> Self {
+        Self {
+            path: path.into(),
+            format,
+            rotate_ticks: rotate_ticks.filter(|ticks| *ticks > 0),
+            metadata: HashMap::new(),
+            file: None,
+            finished: false,
+            line: String::new(),
+        }
+    }
+
+    /// Create the single file at `path` and its parent directory up front, rather than on the
+    /// first row. `metadata` is stored in Arrow and Parquet files.
+    pub fn create(
+        path: impl Into<PathBuf>,
+        format: TrajectoryFormat,
+        metadata: HashMap<String, String>,
+    ) -> io::Result<Self> {
+        let mut writer = Self::new(path, format, None).with_metadata(metadata);
+        writer.open(0)?;
+        Ok(writer)
+    }
+
+    /// Run metadata, such as the seed and parameters, for Arrow and Parquet files.
+    pub fn with_metadata(mut self, metadata: HashMap<String, String>) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Path of the `index`th file.
+    pub fn file_path(&self, index: u64) -> PathBuf {
+        match self.rotate_ticks {
+            Some(_) => rotated_path(&self.path, index),
+            None => self.path.clone(),
+        }
+    }
+
+    pub fn write(&mut self, row: &R) -> io::Result<()> {
+        if self.finished {
+            return Err(io::Error::other(format!(
+                "{} was already finished",
+                self.path.display()
+            )));
+        }
+        let index = self.rotate_ticks.map_or(0, |ticks| row.tick() / ticks);
+        if self.file.as_ref().map(|(open, _)| *open) != Some(index) {
+            self.open(index)?;
+        }
+        #[cfg(feature = "arrow")]
+        if let Some((
+            _,
+            Sink::Columnar {
+                writer,
+                schema,
+                rows,
+            },
+        )) = self.file.as_mut()
+        {
+            if rows.len() >= R::BATCH_ROWS
+                && rows.last().is_some_and(|last| last.tick() != row.tick())
+            {
+                write_batch(writer, schema, rows)?;
+            }
+            rows.push(*row);
+            return Ok(());
+        }
+        if let Some((_, Sink::Arrays { rows, .. })) = self.file.as_mut() {
+            rows.push(*row);
+            return Ok(());
+        }
+
+        self.line.clear();
+        match self.format {
+            TrajectoryFormat::Csv => row.write_csv(&mut self.line),
+            TrajectoryFormat::Ndjson => {
+                self.line = serde_json::to_string(row).map_err(io::Error::other)?;
+            }
+            _ => unreachable!("not a text format"),
+        }
+        self.line.push('\n');
+
+        let Some((_, Sink::Text(file))) = self.file.as_mut() else {
+            unreachable!("file was just opened");
+        };
+        file.write_all(self.line.as_bytes())
+    }
+
+    /// Flush text files, or write buffered rows to Arrow and Parquet files. NumPy arrays are
+    /// only written by [`RowWriter::finish`].
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.file.as_mut() {
+            Some((_, Sink::Text(file))) => file.flush(),
+            #[cfg(feature = "arrow")]
+            Some((
+                _,
+                Sink::Columnar {
+                    writer,
+                    schema,
+                    rows,
+                },
+            )) => write_batch(writer, schema, rows),
+            Some((_, Sink::Arrays { .. })) | None => Ok(()),
+        }
+    }
+
+    /// Flush and close the open file, writing the footer of Arrow and Parquet files or the
+    /// NumPy arrays. Call this after the last row; later rows are an error, and later calls
+    /// do nothing.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.close()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()?;
+        match self.file.take() {
+            #[cfg(feature = "arrow")]
+            Some((_, Sink::Columnar { mut writer, .. })) => {
+                writer.finish().map_err(io::Error::other)
+            }
+            Some((_, Sink::Arrays { path, rows })) => R::write_arrays(&path, self.format, &rows),
+            _ => Ok(()),
+        }
+    }
+
+    fn open(&mut self, index: u64) -> io::Result<()> {
+        self.close()?;
+        if let Some(feature) = self.format.missing_feature() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{:?} files need the `{feature}` feature", self.format),
+            ));
+        }
+        let path = self.file_path(index);
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        let sink = match self.format {
+            #[cfg(feature = "arrow")]
+            TrajectoryFormat::Arrow | TrajectoryFormat::Parquet => {
+                let schema = R::schema(self.metadata.clone());
+                Sink::Columnar {
+                    writer: Box::new(
+                        BatchWriter::create(&path, self.format, schema.clone())
+                            .map_err(io::Error::other)?,
+                    ),
+                    schema,
+                    rows: Vec::new(),
+                }
+            }
+            TrajectoryFormat::Npy | TrajectoryFormat::Npz => Sink::Arrays {
+                path,
+                rows: Vec::new(),
+            },
+            _ => {
+                let mut file = BufWriter::new(File::create(path)?);
+                if self.format == TrajectoryFormat::Csv {
+                    writeln!(file, "{}", R::CSV_HEADER)?;
+                }
+                Sink::Text(file)
+            }
+        };
+        self.file = Some((index, sink));
+        Ok(())
+    }
+}
+
+impl<R: Row> Drop for RowWriter<R> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+#[cfg(feature = "arrow")]
+fn write_batch<R: Row>(
+    writer: &mut BatchWriter,
+    schema: &arrow_schema::SchemaRef,
+    rows: &mut Vec<R>,
+) -> io::Result<()> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    let batch = R::batch(schema.clone(), rows).map_err(io::Error::other)?;
+    rows.clear();
+    writer.write(&batch).map_err(io::Error::other)
+}
+
+/// Write `rows` as NumPy arrays, with the boids sorted by id and NaN where a boid is missing
+/// from a tick:
+///
+/// * `ticks`: `(ticks,)` `uint64`
+/// * `ids`: `(boids,)` `uint64`
+/// * `positions`, `velocities`: `(ticks, boids, 2)` `float32`
+/// * `n_neighbors`: `(ticks, boids)` `float32`
+///
+/// As `.npz` these are the archive's keys, and as `.npy`, `run.npy` is written as
+/// `run.ticks.npy`, `run.ids.npy` and so on.
+pub fn write_arrays(
+    path: &Path,
+    format: TrajectoryFormat,
+    rows: &[TrajectoryRow],
+) -> io::Result<()> {
+    let mut ticks = rows.iter().map(|row| row.tick).collect::<Vec<_>>();
+    ticks.sort_unstable();
+    ticks.dedup();
+    let mut ids = rows.iter().map(|row| row.id).collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+
+    let (n_ticks, n_boids) = (ticks.len(), ids.len());
+    let mut positions = vec![f32::NAN; n_ticks * n_boids * 2];
+    let mut velocities = vec![f32::NAN; n_ticks * n_boids * 2];
+    let mut n_neighbors = vec![f32::NAN; n_ticks * n_boids];
+    for row in rows {
+        // Both are present, having been collected from the rows
+        let t = ticks.binary_search(&row.tick).unwrap();
+        let b = ids.binary_search(&row.id).unwrap();
+        let i = t * n_boids + b;
+        positions[2 * i..2 * i + 2].copy_from_slice(&[row.x, row.y]);
+        velocities[2 * i..2 * i + 2].copy_from_slice(&[row.vx, row.vy]);
+        n_neighbors[i] = row.n_neighbors as f32;
+    }
+
+    let arrays = [
+        ("ticks", npy::write_npy(&[n_ticks], &ticks)),
+        ("ids", npy::write_npy(&[n_boids], &ids)),
+        (
+            "positions",
+            npy::write_npy(&[n_ticks, n_boids, 2], &positions),
+        ),
+        (
+            "velocities",
+            npy::write_npy(&[n_ticks, n_boids, 2], &velocities),
+        ),
+        (
+            "n_neighbors",
+            npy::write_npy(&[n_ticks, n_boids], &n_neighbors),
+        ),
+    ];
+    write_array_files(path, format, &arrays)
+}
+
+/// Write named `.npy` files into one `.npz` archive at `path`, or for
+/// [`TrajectoryFormat::Npy`] as separate `{stem}.{name}.npy` files next to it.
+pub fn write_array_files(
+    path: &Path,
+    format: TrajectoryFormat,
+    arrays: &[(&str, Vec<u8>)],
+) -> io::Result<()> {
+    match format {
+        TrajectoryFormat::Npz => {
+            let files = arrays
+                .iter()
+                .map(|(name, bytes)| (*name, bytes.as_slice()))
+                .collect::<Vec<_>>();
+            let mut file = BufWriter::new(File::create(path)?);
+            npy::write_npz(&mut file, &files)?;
+            file.flush()
+        }
+        _ => {
+            let stem = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("trajectory");
+            for (name, bytes) in arrays {
+                std::fs::write(path.with_file_name(format!("{stem}.{name}.npy")), bytes)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// `stem-0000.ext` style name of the `index`th file when rotating `path`.
+pub fn rotated_path(path: &Path, index: u64) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("trajectory");
+    let name = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => format!("{stem}-{index:04}.{ext}"),
+        None => format!("{stem}-{index:04}"),
+    };
+    path.with_file_name(name)
+}
+
+/// Deterministically pick about `fraction` of all ids, the same ones every tick.
+pub fn is_sampled(id: u64, fraction: f32) -> bool {
+    if fraction >= 1. {
+        return true;
+    }
+    // SplitMix64 finaliser, so consecutive ids are spread out
+    let mut z = id.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    ((z >> 40) as f32 / (1u64 << 24) as f32) < fraction
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn row(tick: u64, id: u64) -> TrajectoryRow {
+        TrajectoryRow {
+            tick,
+            id,
+            x: 1.5,
+            y: -2.,
+            vx: 3.,
+            vy: 0.25,
+            n_neighbors: 4,
+        }
+    }
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(
+            TrajectoryFormat::from_path(Path::new("out/run.ndjson")),
+            TrajectoryFormat::Ndjson
+        );
+        assert_eq!(
+            TrajectoryFormat::from_path(Path::new("run.csv")),
+            TrajectoryFormat::Csv
+        );
+        assert_eq!(
+            TrajectoryFormat::from_path(Path::new("run")),
+            TrajectoryFormat::Csv
+        );
+        assert_eq!(
+            TrajectoryFormat::from_path(Path::new("run.feather")),
+            TrajectoryFormat::Arrow
+        );
+        assert_eq!(
+            TrajectoryFormat::from_path(Path::new("run.parquet")),
+            TrajectoryFormat::Parquet
+        );
+        assert_eq!(
+            TrajectoryFormat::Parquet.missing_feature(),
+            cfg!(not(feature = "parquet")).then_some("parquet")
+        );
+    }
+
+    #[test]
+    fn test_write_csv() {
+        let dir = temp_dir("csv");
+        let path = dir.join("run.csv");
+        let mut writer = TrajectoryWriter::new(&path, TrajectoryFormat::Csv, None);
+        writer.write(&row(0, 7)).unwrap();
+        writer.write(&row(1, 7)).unwrap();
+        writer.flush().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text,
+            format!("{CSV_HEADER}\n0,7,1.5,-2,3,0.25,4\n1,7,1.5,-2,3,0.25,4\n")
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_after_finish() {
+        let dir = temp_dir("finished");
+        let path = dir.join("run.csv");
+        let mut writer = TrajectoryWriter::new(&path, TrajectoryFormat::Csv, None);
+        writer.write(&row(0, 7)).unwrap();
+        writer.finish().unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+
+        assert!(writer.write(&row(1, 7)).is_err());
+        writer.finish().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_arrays_pads_missing_boids() {
+        let dir = temp_dir("npy");
+        let path = dir.join("run.npy");
+        let mut writer = TrajectoryWriter::new(&path, TrajectoryFormat::Npy, None);
+        // Boid 9 is missing from tick 1
+        for (tick, id) in [(0, 9), (0, 3), (1, 3)] {
+            writer.write(&row(tick, id)).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let positions =
+            npy::read_npy(&std::fs::read(dir.join("run.positions.npy")).unwrap()).unwrap();
+        assert_eq!(positions.shape, vec![2, 2, 2]);
+        assert_eq!(positions.data[..6], [1.5, -2., 1.5, -2., 1.5, -2.]);
+        assert!(positions.data[6..].iter().all(|v| v.is_nan()));
+        let n_neighbors =
+            npy::read_npy(&std::fs::read(dir.join("run.n_neighbors.npy")).unwrap()).unwrap();
+        assert_eq!(n_neighbors.shape, vec![2, 2]);
+        assert_eq!(n_neighbors.data[..3], [4., 4., 4.]);
+
+        let path = dir.join("run.npz");
+        let mut writer = TrajectoryWriter::new(&path, TrajectoryFormat::Npz, None);
+        writer.write(&row(0, 1)).unwrap();
+        drop(writer);
+        assert_eq!(std::fs::read(&path).unwrap()[..4], *b"PK\x03\x04");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_ndjson_rotates() {
+        let dir = temp_dir("ndjson");
+        let path = dir.join("run.ndjson");
+        let mut writer = TrajectoryWriter::new(&path, TrajectoryFormat::Ndjson, Some(2));
+        for tick in 0..5 {
+            writer.write(&row(tick, 1)).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let lines = |index| {
+            std::fs::read_to_string(dir.join(format!("run-{index:04}.ndjson")))
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<TrajectoryRow>(line).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(lines(0), vec![row(0, 1), row(1, 1)]);
+        assert_eq!(lines(1), vec![row(2, 1), row(3, 1)]);
+        assert_eq!(lines(2), vec![row(4, 1)]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_is_sampled() {
+        assert!((0..100).all(|id| is_sampled(id, 1.)));
+        assert!((0..100).all(|id| !is_sampled(id, 0.)));
+
+        let n = (0..10000).filter(|id| is_sampled(*id, 0.25)).count();
+        assert!((2300..2700).contains(&n), "{n}");
+        // Stable from tick to tick
+        assert_eq!(is_sampled(12345, 0.5), is_sampled(12345, 0.5));
+    }
+}
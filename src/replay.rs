@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::recorder::{rotated_path, TrajectoryFormat, TrajectoryRow};
+
+/// Trajectory rows grouped into frames by tick, in tick order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Recording {
+    frames: Vec<(u64, Vec<TrajectoryRow>)>,
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(PathBuf, std::io::Error),
+    /// A line (counting from 1) that could not be parsed
+    Parse(PathBuf, usize, String),
+    /// An Arrow or Parquet file that could not be read
+    Columnar(PathBuf, String),
+    /// A format that can't be replayed, or wasn't compiled in
+    Unsupported(PathBuf, String),
+    /// No rows in any file
+    Empty,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(path, err) => write!(f, "could not read {}: {err}", path.display()),
+            ReplayError::Parse(path, line, message) => {
+                write!(f, "{}:{line}: {message}", path.display())
+            }
+            ReplayError::Columnar(path, message) => {
+                write!(f, "could not read {}: {message}", path.display())
+            }
+            ReplayError::Unsupported(path, message) => write!(f, "{}: {message}", path.display()),
+            ReplayError::Empty => write!(f, "recording is empty"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl Recording {
+    pub fn from_rows(rows: impl IntoIterator<Item = TrajectoryRow>) -> Self {
+        let mut frames = BTreeMap::<u64, Vec<TrajectoryRow>>::new();
+        for row in rows {
+            frames.entry(row.tick).or_default().push(row);
+        }
+        Self {
+            frames: frames.into_iter().collect(),
+        }
+    }
+
+    /// Read `path`, or if it doesn't exist, the numbered files written when rotating it.
+    pub fn load(path: &Path) -> Result<Self, ReplayError> {
+        let format = TrajectoryFormat::from_path(path);
+        if let Some(feature) = format.missing_feature() {
+            let message = format!("{format:?} files need the `{feature}` feature");
+            return Err(ReplayError::Unsupported(path.to_path_buf(), message));
+        }
+        if matches!(format, TrajectoryFormat::Npy | TrajectoryFormat::Npz) {
+            let message =
+                "NumPy recordings can't be replayed, record CSV, NDJSON, Arrow or Parquet";
+            return Err(ReplayError::Unsupported(path.to_path_buf(), message.into()));
+        }
+
+        let mut rows = Vec::new();
+        for file in recording_files(path) {
+            match format {
+                #[cfg(feature = "arrow")]
+                TrajectoryFormat::Arrow | TrajectoryFormat::Parquet => {
+                    let (file_rows, _) = crate::columnar::read_trajectories(&file, format)
+                        .map_err(|err| ReplayError::Columnar(file.clone(), err.to_string()))?;
+                    rows.extend(file_rows);
+                }
+                _ => {
+                    let Some(text_format) = TextFormat::of(format) else {
+                        unreachable!("{format:?} files were screened out above");
+                    };
+                    let text = std::fs::read_to_string(&file)
+                        .map_err(|err| ReplayError::Io(file.clone(), err))?;
+                    rows.extend(parse_rows(&text, text_format).map_err(|(line, message)| {
+                        ReplayError::Parse(file.clone(), line, message)
+                    })?);
+                }
+            }
+        }
+        if rows.is_empty() {
+            return Err(ReplayError::Empty);
+        }
+        Ok(Self::from_rows(rows))
+    }
+
+    /// Number of frames.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn first_tick(&self) -> u64 {
+        self.frames.first().map_or(0, |(tick, _)| *tick)
+    }
+
+    pub fn last_tick(&self) -> u64 {
+        self.frames.last().map_or(0, |(tick, _)| *tick)
+    }
+
+    /// Index of the last frame at or before `tick`, or 0 if `tick` is before the first frame.
+    pub fn frame_index(&self, tick: f64) -> usize {
+        self.frames
+            .partition_point(|(frame_tick, _)| *frame_tick as f64 <= tick)
+            .saturating_sub(1)
+    }
+
+    /// Tick and rows of the `index`th frame.
+    pub fn frame(&self, index: usize) -> Option<(u64, &[TrajectoryRow])> {
+        self.frames
+            .get(index)
+            .map(|(tick, rows)| (*tick, rows.as_slice()))
+    }
+
+    /// The last frame at or before `tick`, or the first frame if `tick` is before it.
+    pub fn frame_at(&self, tick: f64) -> Option<(u64, &[TrajectoryRow])> {
+        self.frame(self.frame_index(tick))
+    }
+}
+
+/// `path` if it exists, otherwise its rotated files `stem-0000.ext`, `stem-0001.ext`, ... up to
+/// the first missing one.
+pub fn recording_files(path: &Path) -> Vec<PathBuf> {
+    if path.exists() {
+        return vec![path.to_path_buf()];
+    }
+    (0..)
+        .map(|index| rotated_path(path, index))
+        .take_while(|file| file.exists())
+        .collect()
+}
+
+/// The trajectory formats written as lines of text.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextFormat {
+    Csv,
+    Ndjson,
+}
+
+impl TextFormat {
+    /// `format` if it's a text format.
+    pub fn of(format: TrajectoryFormat) -> Option<Self> {
+        match format {
+            TrajectoryFormat::Csv => Some(TextFormat::Csv),
+            TrajectoryFormat::Ndjson => Some(TextFormat::Ndjson),
+            _ => None,
+        }
+    }
+}
+
+/// Parse CSV (with or without its header) or NDJSON rows. Errors give the line number.
+pub fn parse_rows(text: &str, format: TextFormat) -> Result<Vec<TrajectoryRow>, (usize, String)> {
+    let mut rows = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("tick") {
+            continue;
+        }
+        let row = match format {
+            TextFormat::Csv => parse_csv_row(line),
+            TextFormat::Ndjson => serde_json::from_str(line).map_err(|err| err.to_string()),
+        };
+        rows.push(row.map_err(|message| (i + 1, message))?);
+    }
+    Ok(rows)
+}
+
+fn parse_csv_row(line: &str) -> Result<TrajectoryRow, String> {
+    let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+    let [tick, id, x, y, vx, vy, n_neighbors] = fields[..] else {
+        return Err(format!("expected 7 fields, found {}", fields.len()));
+    };
+    let float = |field: &str| {
+        field
+            .parse::<f32>()
+            .map_err(|_| format!("invalid number {field}"))
+    };
+    let integer = |field: &str| {
+        field
+            .parse::<u64>()
+            .map_err(|_| format!("invalid integer {field}"))
+    };
+    Ok(TrajectoryRow {
+        tick: integer(tick)?,
+        id: integer(id)?,
+        x: float(x)?,
+        y: float(y)?,
+        vx: float(vx)?,
+        vy: float(vy)?,
+        n_neighbors: integer(n_neighbors)? as u32,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::recorder::TrajectoryWriter;
+    use crate::test_util::{row, temp_dir};
+
+    #[test]
+    fn test_frame_at() {
+        let recording = Recording::from_rows([row(4, 1), row(0, 1), row(0, 2), row(2, 1)]);
+        assert_eq!(recording.len(), 3);
+        assert_eq!((recording.first_tick(), recording.last_tick()), (0, 4));
+
+        let (tick, rows) = recording.frame_at(0.).unwrap();
+        assert_eq!((tick, rows), (0, &[row(0, 1), row(0, 2)][..]));
+        assert_eq!(recording.frame_at(1.9).unwrap().0, 0);
+        assert_eq!(recording.frame_at(2.).unwrap().0, 2);
+        assert_eq!(recording.frame_at(100.).unwrap().0, 4);
+        assert_eq!(recording.frame_at(-5.).unwrap().0, 0);
+        assert!(Recording::default().frame_at(0.).is_none());
+
+        assert_eq!(recording.frame_index(3.), 1);
+        assert_eq!(recording.frame(2).unwrap().0, 4);
+        assert!(recording.frame(3).is_none());
+    }
+
+    #[test]
+    fn test_parse_rows() {
+        let csv = "tick,id,x,y,vx,vy,n_neighbors\n0,1,0,1,1,-1,3\n\n2,5,2,5,1,-1,3\n";
+        assert_eq!(
+            parse_rows(csv, TextFormat::Csv).unwrap(),
+            vec![row(0, 1), row(2, 5)]
+        );
+
+        let ndjson = r#"{"tick":0,"id":1,"x":0,"y":1,"vx":1,"vy":-1,"n_neighbors":3}"#;
+        assert_eq!(
+            parse_rows(ndjson, TextFormat::Ndjson).unwrap(),
+            vec![row(0, 1)]
+        );
+
+        assert_eq!(
+            parse_rows("0,1,0,1,1,-1,3\n0,1,x,1,1,-1,3", TextFormat::Csv)
+                .unwrap_err()
+                .0,
+            2
+        );
+        assert!(parse_rows("0,1,2", TextFormat::Csv).is_err());
+    }
+
+    #[test]
+    fn test_load_rotated_files() {
+        let dir = temp_dir("replay");
+        let path = dir.join("run.csv");
+
+        let mut writer = TrajectoryWriter::new(&path, TrajectoryFormat::Csv, Some(2));
+        for tick in 0..5 {
+            writer.write(&row(tick, 9)).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        assert_eq!(recording_files(&path).len(), 3);
+        let recording = Recording::load(&path).unwrap();
+        assert_eq!(recording.len(), 5);
+        assert_eq!(recording.frame_at(3.).unwrap().1, &[row(3, 9)]);
+
+        assert!(matches!(
+            Recording::load(&dir.join("missing.csv")),
+            Err(ReplayError::Empty)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt;
+use std::path::Path;
+
+use rand_chacha::ChaCha8Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// Everything needed to restore a simulation exactly: the parameters `P`, the random number
+/// generator mid-stream, the spatial index cell size and every boid.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Snapshot<P> {
+    pub params: P,
+    pub rng: ChaCha8Rng,
+    pub cell_size: f32,
+    pub boids: Vec<BoidState>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct BoidState {
+    /// Id in recordings, missing from older snapshots
+    #[serde(default)]
+    pub id: Option<u64>,
+    pub position: [f32; 2],
+    pub velocity: [f32; 2],
+    /// Wander angle or turning rate
+    #[serde(default)]
+    pub wander: f32,
+    /// Goal heading, for leaders
+    #[serde(default)]
+    pub leader_heading: Option<[f32; 2]>,
+    #[serde(default)]
+    pub player_controlled: bool,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    Serialize(ron::Error),
+    Deserialize(ron::error::SpannedError),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "could not access snapshot: {err}"),
+            SnapshotError::Serialize(err) => write!(f, "could not write snapshot: {err}"),
+            SnapshotError::Deserialize(err) => write!(f, "invalid snapshot: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(err: std::io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+impl From<ron::Error> for SnapshotError {
+    fn from(err: ron::Error) -> Self {
+        SnapshotError::Serialize(err)
+    }
+}
+
+impl From<ron::error::SpannedError> for SnapshotError {
+    fn from(err: ron::error::SpannedError) -> Self {
+        SnapshotError::Deserialize(err)
+    }
+}
+
+impl<P: Serialize + DeserializeOwned> Snapshot<P> {
+    pub fn to_ron(&self) -> Result<String, SnapshotError> {
+        Ok(ron::ser::to_string_pretty(
+            self,
+            ron::ser::PrettyConfig::default(),
+        )?)
+    }
+
+    pub fn from_ron(ron: &str) -> Result<Self, SnapshotError> {
+        Ok(ron::from_str(ron)?)
+    }
+
+    /// Write the snapshot as RON, creating parent directories as needed.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let path = path.as_ref();
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, self.to_ron()?)?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SnapshotError> {
+        Self::from_ron(&std::fs::read_to_string(path)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    struct Params {
+        speed: f32,
+    }
+
+    fn snapshot() -> Snapshot<Params> {
+        Snapshot {
+            params: Params { speed: 2.5 },
+            rng: ChaCha8Rng::seed_from_u64(7),
+            cell_size: 26.5,
+            boids: vec![
+                BoidState {
+                    id: Some(0),
+                    position: [1., -2.],
+                    velocity: [3., 4.],
+                    wander: 0.25,
+                    leader_heading: Some([1., 0.]),
+                    player_controlled: true,
+                },
+                BoidState {
+                    id: None,
+                    position: [0.1, 0.2],
+                    velocity: [-5., 6.],
+                    wander: 0.,
+                    leader_heading: None,
+                    player_controlled: false,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_ron_round_trip() {
+        let snapshot = snapshot();
+        let restored = Snapshot::<Params>::from_ron(&snapshot.to_ron().unwrap()).unwrap();
+        assert_eq!(restored, snapshot);
+    }
+
+    #[test]
+    fn test_rng_resumes_mid_stream() {
+        let mut snapshot = snapshot();
+        let _: [u32; 5] = snapshot.rng.gen();
+        let mut restored = Snapshot::<Params>::from_ron(&snapshot.to_ron().unwrap()).unwrap();
+        for _ in 0..10 {
+            assert_eq!(restored.rng.gen::<u64>(), snapshot.rng.gen::<u64>());
+        }
+    }
+
+    #[test]
+    fn test_optional_boid_fields() {
+        let ron = "(
+            params: (speed: 1.0),
+            rng: (seed: (0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0), stream: 0, word_pos: 0),
+            cell_size: 10.0,
+            boids: [(position: (1.0, 2.0), velocity: (3.0, 4.0))],
+        )";
+        let snapshot = Snapshot::<Params>::from_ron(ron).unwrap();
+        assert_eq!(snapshot.boids[0].wander, 0.);
+        assert_eq!(snapshot.boids[0].leader_heading, None);
+        assert!(!snapshot.boids[0].player_controlled);
+
+        assert!(matches!(
+            Snapshot::<Params>::from_ron("(params: ())"),
+            Err(SnapshotError::Deserialize(_))
+        ));
+    }
+}
@@ -0,0 +1,39 @@
+//! Fixtures shared by the unit tests.
+
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+
+use crate::recorder::TrajectoryRow;
+use crate::voxel::VoxelHashMap;
+
+/// A trajectory row whose position is its tick and id, so rows are easy to tell apart.
+pub fn row(tick: u64, id: u64) -> TrajectoryRow {
+    TrajectoryRow {
+        tick,
+        id,
+        x: tick as f32,
+        y: id as f32,
+        vx: 1.,
+        vy: -1.,
+        n_neighbors: 3,
+    }
+}
+
+/// A directory under the system temp directory for the test `name`, unique to this process and
+/// removed if a previous run left it behind. Writers create it as needed.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("bevy_boids_{name}_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// A voxel hash map of `boids` (entity, position and velocity) with the given cell size.
+pub fn voxels(boids: &[(Entity, Vec2, Vec2)], cell_size: f32) -> VoxelHashMap {
+    let mut voxels = VoxelHashMap::new();
+    voxels.cell_size = cell_size;
+    for (entity, position, _) in boids {
+        voxels.insert(*position, *entity);
+    }
+    voxels
+}
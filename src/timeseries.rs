@@ -0,0 +1,274 @@
+#[cfg(feature = "arrow")]
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+
+use bevy::math::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::npy;
+use crate::recorder::{write_array_files, Row, RowWriter, TrajectoryFormat};
+
+#[cfg(feature = "arrow")]
+use crate::columnar::{metrics_batch, metrics_schema};
+
+/// Collective metrics of the whole flock at one tick. NaN where a metric is undefined, such as
+/// the nearest-neighbour distance of a lone boid.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct MetricsRow {
+    pub tick: u64,
+    /// Simulated seconds
+    pub time: f64,
+    pub polarization: f32,
+    pub milling: f32,
+    pub nearest_neighbor_distance: f32,
+    pub group_radius: f32,
+    pub mean_speed: f32,
+    pub clusters: u32,
+    pub largest_cluster: u32,
+    /// Smoothed frames per second of the app, not the simulation
+    pub fps: f32,
+}
+
+impl Default for MetricsRow {
+    fn default() -> Self {
+        Self {
+            tick: 0,
+            time: 0.,
+            polarization: f32::NAN,
+            milling: f32::NAN,
+            nearest_neighbor_distance: f32::NAN,
+            group_radius: f32::NAN,
+            mean_speed: f32::NAN,
+            clusters: 0,
+            largest_cluster: 0,
+            fps: f32::NAN,
+        }
+    }
+}
+
+pub const METRICS_CSV_HEADER: &str = "tick,time,polarization,milling,nearest_neighbor_distance,\
+group_radius,mean_speed,clusters,largest_cluster,fps";
+
+/// Rows per record batch in Arrow and Parquet metrics files.
+const METRICS_BATCH_ROWS: usize = 4096;
+
+/// Writes a metrics row per tick to a file as CSV, NDJSON, Arrow, Parquet or NumPy arrays,
+/// chosen like trajectory files by [`TrajectoryFormat::from_path`]. As NumPy arrays each column
+/// is a 1-D array named after it, kept in memory until [`RowWriter::finish`].
+pub type MetricsWriter = RowWriter<MetricsRow>;
+
+impl Row for MetricsRow {
+    const CSV_HEADER: &'static str = METRICS_CSV_HEADER;
+    const BATCH_ROWS: usize = METRICS_BATCH_ROWS;
+
+    fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    fn write_csv(&self, line: &mut String) {
+        let MetricsRow {
+            tick,
+            time,
+            polarization,
+            milling,
+            nearest_neighbor_distance,
+            group_radius,
+            mean_speed,
+            clusters,
+            largest_cluster,
+            fps,
+        } = self;
+        let _ = write!(
+            line,
+            "{tick},{time},{polarization},{milling},{nearest_neighbor_distance},\
+             {group_radius},{mean_speed},{clusters},{largest_cluster},{fps}"
+        );
+    }
+
+    #[cfg(feature = "arrow")]
+    fn schema(metadata: HashMap<String, String>) -> arrow_schema::SchemaRef {
+        metrics_schema(metadata)
+    }
+
+    #[cfg(feature = "arrow")]
+    fn batch(
+        schema: arrow_schema::SchemaRef,
+        rows: &[Self],
+    ) -> Result<arrow_array::RecordBatch, arrow_schema::ArrowError> {
+        metrics_batch(schema, rows)
+    }
+
+    fn write_arrays(path: &Path, format: TrajectoryFormat, rows: &[Self]) -> io::Result<()> {
+        write_metrics_arrays(path, format, rows)
+    }
+}
+
+fn write_metrics_arrays(
+    path: &Path,
+    format: TrajectoryFormat,
+    rows: &[MetricsRow],
+) -> io::Result<()> {
+    let n = rows.len();
+    let f32s =
+        |f: fn(&MetricsRow) -> f32| npy::write_npy(&[n], &rows.iter().map(f).collect::<Vec<_>>());
+    let u32s =
+        |f: fn(&MetricsRow) -> u32| npy::write_npy(&[n], &rows.iter().map(f).collect::<Vec<_>>());
+    let arrays = [
+        (
+            "tick",
+            npy::write_npy(&[n], &rows.iter().map(|row| row.tick).collect::<Vec<_>>()),
+        ),
+        (
+            "time",
+            npy::write_npy(&[n], &rows.iter().map(|row| row.time).collect::<Vec<_>>()),
+        ),
+        ("polarization", f32s(|row| row.polarization)),
+        ("milling", f32s(|row| row.milling)),
+        (
+            "nearest_neighbor_distance",
+            f32s(|row| row.nearest_neighbor_distance),
+        ),
+        ("group_radius", f32s(|row| row.group_radius)),
+        ("mean_speed", f32s(|row| row.mean_speed)),
+        ("clusters", u32s(|row| row.clusters)),
+        ("largest_cluster", u32s(|row| row.largest_cluster)),
+        ("fps", f32s(|row| row.fps)),
+    ];
+    write_array_files(path, format, &arrays)
+}
+
+/// The last `capacity` values of a metric, for plotting.
+#[derive(Clone, Debug, Default)]
+pub struct RollingSeries {
+    values: VecDeque<f32>,
+    capacity: usize,
+}
+
+impl RollingSeries {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            values: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Append `value`, dropping the oldest once full.
+    pub fn push(&mut self, value: f32) {
+        if self.values.len() == self.capacity {
+            self.values.pop_front();
+        }
+        self.values.push_back(value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn latest(&self) -> Option<f32> {
+        self.values.back().copied()
+    }
+
+    /// Largest value in the window, ignoring NaN.
+    pub fn max(&self) -> Option<f32> {
+        self.values
+            .iter()
+            .copied()
+            .filter(|value| !value.is_nan())
+            .reduce(f32::max)
+    }
+
+    /// Points in the unit square, with the newest value at x = 1 and a full window spanning
+    /// x = 0 to 1, and `low..high` mapped to y = 0 to 1 (clamped). NaN values are skipped.
+    pub fn normalized_points(&self, low: f32, high: f32) -> impl Iterator<Item = Vec2> + '_ {
+        let step = 1. / self.capacity.saturating_sub(1).max(1) as f32;
+        let start = 1. - (self.values.len().saturating_sub(1)) as f32 * step;
+        let range = (high - low).max(f32::EPSILON);
+        self.values
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| !value.is_nan())
+            .map(move |(i, value)| {
+                Vec2::new(
+                    start + i as f32 * step,
+                    ((value - low) / range).clamp(0., 1.),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn row(tick: u64) -> MetricsRow {
+        MetricsRow {
+            tick,
+            time: tick as f64 / 60.,
+            polarization: 0.5,
+            clusters: 3,
+            largest_cluster: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_write_text() {
+        let dir = temp_dir("metrics");
+        let csv = dir.join("metrics.csv");
+        let mut writer =
+            MetricsWriter::create(&csv, TrajectoryFormat::Csv, HashMap::new()).unwrap();
+        writer.write(&row(0)).unwrap();
+        writer.write(&row(60)).unwrap();
+        writer.finish().unwrap();
+        let text = std::fs::read_to_string(&csv).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], METRICS_CSV_HEADER);
+        assert_eq!(lines[2], "60,1,0.5,NaN,NaN,NaN,NaN,3,10,NaN");
+        assert_eq!(lines.len(), 3);
+
+        // Undefined metrics are null in JSON
+        let ndjson = dir.join("metrics.ndjson");
+        let mut writer =
+            MetricsWriter::create(&ndjson, TrajectoryFormat::Ndjson, HashMap::new()).unwrap();
+        writer.write(&row(60)).unwrap();
+        writer.finish().unwrap();
+        let json = std::fs::read_to_string(&ndjson).unwrap();
+        let value = serde_json::from_str::<serde_json::Value>(json.trim()).unwrap();
+        assert_eq!(value["clusters"], 3);
+        assert!(value["milling"].is_null());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rolling_series() {
+        let mut series = RollingSeries::new(3);
+        assert_eq!(series.max(), None);
+        for value in [1., 5., f32::NAN, 2.] {
+            series.push(value);
+        }
+        // The first value was dropped
+        assert_eq!(series.len(), 3);
+        assert_eq!(series.latest(), Some(2.));
+        assert_eq!(series.max(), Some(5.));
+
+        let points = series.normalized_points(0., 10.).collect::<Vec<_>>();
+        assert_eq!(points, vec![Vec2::new(0., 0.5), Vec2::new(1., 0.2)]);
+
+        // A partly filled window ends at the right edge
+        let mut series = RollingSeries::new(5);
+        series.push(20.);
+        series.push(-1.);
+        let points = series.normalized_points(0., 10.).collect::<Vec<_>>();
+        assert_eq!(points, vec![Vec2::new(0.75, 1.), Vec2::new(1., 0.)]);
+    }
+}
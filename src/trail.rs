@@ -0,0 +1,210 @@
+use bevy::prelude::*;
+use bevy::render::mesh::{PrimitiveTopology, VertexAttributeValues};
+use bevy::render::render_asset::RenderAssetUsages;
+
+/// Fixed-capacity ring buffer of a boid's recent positions, oldest first.
+#[derive(Component, Clone, Debug)]
+pub struct Trail {
+    points: Vec<Vec2>,
+    /// Index of the oldest point once the buffer is full
+    head: usize,
+    capacity: usize,
+}
+
+impl Trail {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            points: Vec::with_capacity(capacity),
+            head: 0,
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Record a position, overwriting the oldest once full.
+    pub fn push(&mut self, point: Vec2) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.points.len() < self.capacity {
+            self.points.push(point);
+        } else {
+            self.points[self.head] = point;
+            self.head = (self.head + 1) % self.capacity;
+        }
+    }
+
+    /// Positions from oldest to newest.
+    pub fn iter(&self) -> impl Iterator<Item = Vec2> + Clone + '_ {
+        self.points[self.head..]
+            .iter()
+            .chain(&self.points[..self.head])
+            .copied()
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.head = 0;
+    }
+}
+
+/// Trails of many boids merged into one line list mesh, fading from transparent at the oldest
+/// point to the given colour at the newest.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct TrailMesh {
+    positions: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+}
+
+impl TrailMesh {
+    /// Number of line segments.
+    pub fn len(&self) -> usize {
+        self.positions.len() / 2
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.positions.clear();
+        self.colors.clear();
+    }
+
+    /// Append the segments of `trail` ending at `head`, the boid's current position. Steps
+    /// longer than `max_step` are skipped so wrapping around a periodic boundary doesn't draw
+    /// a line across the screen.
+    pub fn push(&mut self, trail: &Trail, head: Vec2, color: LinearRgba, max_step: f32) {
+        let n = trail.len() + 1;
+        let points = trail.iter().chain(std::iter::once(head));
+        for (i, (a, b)) in points.clone().zip(points.skip(1)).enumerate() {
+            if a.distance_squared(b) > max_step * max_step {
+                continue;
+            }
+            for (j, point) in [(i, a), (i + 1, b)] {
+                self.positions.push(point.extend(0.).to_array());
+                self.colors.push(
+                    color
+                        .with_alpha(color.alpha * j as f32 / (n - 1) as f32)
+                        .to_f32_array(),
+                );
+            }
+        }
+    }
+
+    /// An empty mesh with the attributes written by [`TrailMesh::write`].
+    pub fn empty_mesh() -> Mesh {
+        let mut mesh = Mesh::new(
+            PrimitiveTopology::LineList,
+            RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
+        );
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, Vec::<[f32; 3]>::new());
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, Vec::<[f32; 4]>::new());
+        mesh
+    }
+
+    /// Move the buffers into `mesh`, leaving this empty. The mesh's previous buffers are taken
+    /// back to be refilled, so their allocations are reused from frame to frame.
+    pub fn write(&mut self, mesh: &mut Mesh) {
+        let positions = std::mem::take(&mut self.positions);
+        let colors = std::mem::take(&mut self.colors);
+        if let Some(VertexAttributeValues::Float32x3(mut previous)) =
+            mesh.remove_attribute(Mesh::ATTRIBUTE_POSITION)
+        {
+            previous.clear();
+            self.positions = previous;
+        }
+        if let Some(VertexAttributeValues::Float32x4(mut previous)) =
+            mesh.remove_attribute(Mesh::ATTRIBUTE_COLOR)
+        {
+            previous.clear();
+            self.colors = previous;
+        }
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_trail_ring_buffer() {
+        let mut trail = Trail::new(3);
+        assert!(trail.is_empty());
+
+        trail.push(Vec2::splat(1.));
+        trail.push(Vec2::splat(2.));
+        assert_eq!(
+            trail.iter().collect::<Vec<_>>(),
+            [Vec2::splat(1.), Vec2::splat(2.)]
+        );
+
+        trail.push(Vec2::splat(3.));
+        trail.push(Vec2::splat(4.));
+        trail.push(Vec2::splat(5.));
+        assert_eq!(trail.len(), 3);
+        assert_eq!(
+            trail.iter().collect::<Vec<_>>(),
+            [Vec2::splat(3.), Vec2::splat(4.), Vec2::splat(5.)]
+        );
+
+        trail.clear();
+        assert!(trail.is_empty());
+        assert_eq!(trail.iter().count(), 0);
+
+        // A zero-length trail never records anything
+        let mut trail = Trail::new(0);
+        trail.push(Vec2::ONE);
+        assert!(trail.is_empty());
+    }
+
+    #[test]
+    fn test_trail_mesh_fades() {
+        let mut trail = Trail::new(2);
+        trail.push(Vec2::new(0., 0.));
+        trail.push(Vec2::new(1., 0.));
+
+        let mut mesh = TrailMesh::default();
+        mesh.push(&trail, Vec2::new(2., 0.), LinearRgba::WHITE, 10.);
+        assert_eq!(mesh.len(), 2);
+        assert_eq!(
+            mesh.positions,
+            vec![[0., 0., 0.], [1., 0., 0.], [1., 0., 0.], [2., 0., 0.]]
+        );
+        let alphas = mesh.colors.iter().map(|c| c[3]).collect::<Vec<_>>();
+        assert_eq!(alphas, vec![0., 0.5, 0.5, 1.]);
+
+        let mut target = TrailMesh::empty_mesh();
+        mesh.write(&mut target);
+        assert!(mesh.is_empty());
+        assert_eq!(target.count_vertices(), 4);
+    }
+
+    #[test]
+    fn test_trail_mesh_skips_wraps() {
+        let mut trail = Trail::new(2);
+        trail.push(Vec2::new(0., 0.));
+        trail.push(Vec2::new(100., 0.));
+
+        let mut mesh = TrailMesh::default();
+        mesh.push(&trail, Vec2::new(101., 0.), LinearRgba::WHITE, 10.);
+        assert_eq!(mesh.len(), 1);
+        assert_eq!(mesh.positions, vec![[100., 0., 0.], [101., 0., 0.]]);
+
+        mesh.clear();
+        assert!(mesh.is_empty());
+    }
+}
@@ -0,0 +1,50 @@
+use bevy::prelude::*;
+
+/// One Euler-Maruyama step of an Ornstein-Uhlenbeck process relaxing to zero with time constant
+/// `tau` and noise amplitude `sigma`. `normal` is a standard normal sample.
+pub fn ornstein_uhlenbeck_step(x: f32, tau: f32, sigma: f32, dt: f32, normal: f32) -> f32 {
+    x - x / tau * dt + sigma * dt.sqrt() * normal
+}
+
+/// Reynolds' wander: the direction towards a point at `angle` on a circle of `radius` projected
+/// `distance` ahead of the boid. Both lengths are in units of the boid's speed.
+pub fn wander_circle_direction(heading: Vec2, angle: f32, distance: f32, radius: f32) -> Vec2 {
+    let heading = heading.normalize_or(Vec2::X);
+    (heading * distance + heading.rotate(Vec2::from_angle(angle)) * radius).normalize_or(heading)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ornstein_uhlenbeck_relaxes() {
+        // Without noise the process decays exponentially
+        let mut x = 1.;
+        for _ in 0..1000 {
+            x = ornstein_uhlenbeck_step(x, 0.5, 1., 0.001, 0.);
+        }
+        assert!((x - (-2f32).exp()).abs() < 1e-2);
+    }
+
+    #[test]
+    fn test_ornstein_uhlenbeck_noise_scales_with_dt() {
+        assert_eq!(ornstein_uhlenbeck_step(0., 1., 2., 0.25, 1.), 1.);
+        assert_eq!(ornstein_uhlenbeck_step(0., 1., 2., 0.25, -1.), -1.);
+    }
+
+    #[test]
+    fn test_wander_circle_direction() {
+        // Straight ahead when the wander point is in front
+        let dir = wander_circle_direction(Vec2::new(2., 0.), 0., 2., 1.);
+        assert!(dir.abs_diff_eq(Vec2::X, 1e-6));
+
+        // Quarter turn to the left, relative to the heading
+        let dir = wander_circle_direction(Vec2::Y, std::f32::consts::FRAC_PI_2, 1., 1.);
+        assert!(dir.abs_diff_eq(Vec2::new(-1., 1.).normalize(), 1e-6));
+
+        // Falls back to a default heading for a stationary boid
+        let dir = wander_circle_direction(Vec2::ZERO, 0., 1., 1.);
+        assert!(dir.abs_diff_eq(Vec2::X, 1e-6));
+    }
+}